@@ -88,6 +88,8 @@ pub struct Bulb {
     sat: u16,
     bri: u16,
     kel: u16,
+    kel_min: u16,
+    kel_max: u16,
 }
 
 pub enum BulbMsg {
@@ -97,7 +99,7 @@ pub enum BulbMsg {
     BriInput(String),
     KelInput(String),
     Refresh,
-    Status(u16, u16, u16, u16),
+    Status(u16, u16, u16, u16, u16, u16),
     Ignore
 }
 
@@ -118,6 +120,8 @@ struct ManualStatus {
     sat: u16,
     bri: u16,
     k: u16,
+    min_k: u16,
+    max_k: u16,
 }
 
 impl Bulb {
@@ -161,7 +165,7 @@ impl Bulb {
                 ConsoleService::log(format!("refresh status result -> {:?} {:?}", meta, data).as_str());
                 match data {
                     Ok(x) => 
-                        BulbMsg::Status(x.hue,x.sat,x.bri,x.k),
+                        BulbMsg::Status(x.hue,x.sat,x.bri,x.k,x.min_k,x.max_k),
                     Err(e) => {
                         ConsoleService::log(format!("{:?}", e).as_str());
                         BulbMsg::Ignore
@@ -194,6 +198,8 @@ impl Component for Bulb {
             sat: props.sat,
             bri: props.bri,
             kel: props.kel,
+            kel_min: 2000,
+            kel_max: 9000,
         }
     }
 
@@ -234,11 +240,13 @@ impl Component for Bulb {
             BulbMsg::Refresh => {
                 self.ft = Some(self.call_refresh());
             }
-            BulbMsg::Status(hue, sat, bri, kel) => {
+            BulbMsg::Status(hue, sat, bri, kel, kel_min, kel_max) => {
                 self.hue = hue;
                 self.sat = sat;
                 self.bri = bri;
                 self.kel = kel;
+                self.kel_min = kel_min;
+                self.kel_max = kel_max;
             }
             BulbMsg::Ignore => {}
         };
@@ -283,7 +291,7 @@ impl Component for Bulb {
                     <li>
                       <div class="form-group">
                           <label for="k">{ "K" }</label>
-                          <input type="range" class="custom-range" min="2000" max="9000" id="k" name="k" step="1" value=self.kel
+                          <input type="range" class="custom-range" min=self.kel_min max=self.kel_max id="k" name="k" step="1" value=self.kel
                                 oninput=self.link.callback(|e: InputData| BulbMsg::KelInput(e.value)) />
                           <span id="k-span" class="font-weight-bold text-primary ml-2 mt-1 valueSpan"/>
                       </div>
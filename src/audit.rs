@@ -0,0 +1,199 @@
+// A record of who changed what, kept as json lines so it can be read with jq as
+// easily as through the api.
+
+use actix::prelude::*;
+use chrono::{DateTime, FixedOffset};
+use lifx_core::HSBK;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// The most entries kept in memory, and so the most a query gives back.
+const AUDIT_MAX_ENTRIES: usize = 1000;
+
+/// Who asked for a change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Source {
+    // The token is the name from the auth file, never the secret.
+    Http {
+        client: Option<String>,
+        token: Option<String>,
+    },
+    Scheduler,
+    Mqtt {
+        topic: String,
+    },
+}
+
+impl Source {
+    /// Who is asking over http, with the name of their token. The peer address
+    /// is used rather than any forwarded header, which the client could make up.
+    pub fn from_http(req: &actix_web::HttpRequest, token: Option<String>) -> Self {
+        Source::Http {
+            client: req.peer_addr().map(|a| a.ip().to_string()),
+            token,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Colour {
+    pub hue: u16,
+    pub sat: u16,
+    pub bri: u16,
+    pub k: u16,
+}
+
+impl From<HSBK> for Colour {
+    fn from(c: HSBK) -> Self {
+        Colour {
+            hue: c.hue,
+            sat: c.saturation,
+            bri: c.brightness,
+            k: c.kelvin,
+        }
+    }
+}
+
+/// A bulb's plan, and its colour if known. After a plan change the colour is
+/// only known once the plan runs, unless it was set by hand.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BulbState {
+    pub plan: String,
+    pub colour: Option<Colour>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BulbChange {
+    pub bulb: String,
+    pub before: BulbState,
+    pub after: BulbState,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    // rfc3339 in the plan timezone.
+    pub at: String,
+    // Such as manual, reset, party_start or presence.
+    pub command: String,
+    pub source: Source,
+    // Anything else about the command, such as how long a party is for.
+    #[serde(default)]
+    pub detail: Option<String>,
+    pub bulbs: Vec<BulbChange>,
+}
+
+impl AuditEntry {
+    fn since(&self, since: &Option<DateTime<FixedOffset>>) -> bool {
+        match since {
+            Some(since) => DateTime::parse_from_rfc3339(&self.at)
+                .map(|at| at >= *since)
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+pub struct AuditRecord(pub AuditEntry);
+
+impl Message for AuditRecord {
+    type Result = ();
+}
+
+/// The entries at or after since, oldest first, up to the latest thousand.
+pub struct AuditQuery {
+    pub since: Option<DateTime<FixedOffset>>,
+}
+
+impl Message for AuditQuery {
+    type Result = Result<Vec<AuditEntry>, String>;
+}
+
+pub struct AuditActor {
+    // Appended to as entries come in, so they last across restarts.
+    file: Option<(PathBuf, File)>,
+    // The latest entries, which queries are answered from. With a file these
+    // are read from it once at the start.
+    recent: VecDeque<AuditEntry>,
+}
+
+impl AuditActor {
+    /// Keep only the latest entries, in memory.
+    pub fn new() -> Self {
+        AuditActor {
+            file: None,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Append to this file, creating it if needed, starting from the latest
+    /// entries already in it.
+    pub fn with_file(mut self, path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Unable to open audit log {} -> {}", path.display(), e))?;
+        for line in BufReader::new(&file).lines() {
+            let line = line.map_err(|e| format!("Unable to read audit log -> {}", e))?;
+            match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) => self.push(entry),
+                // Most likely a line cut short by a crash, so carry on.
+                Err(e) => warn!("Skipping audit log line {} -> {}", line, e),
+            }
+        }
+        self.file = Some((path.to_path_buf(), file));
+        Ok(self)
+    }
+
+    fn push(&mut self, entry: AuditEntry) {
+        if self.recent.len() == AUDIT_MAX_ENTRIES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry);
+    }
+}
+
+impl Default for AuditActor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Actor for AuditActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<AuditRecord> for AuditActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AuditRecord, _ctx: &mut Context<Self>) -> Self::Result {
+        let entry = msg.0;
+        if let Some((path, file)) = &mut self.file {
+            let written = serde_json::to_string(&entry)
+                .map_err(|e| e.to_string())
+                .and_then(|line| writeln!(file, "{}", line).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                error!("Unable to write audit log {} -> {}", path.display(), e);
+            }
+        }
+        self.push(entry);
+    }
+}
+
+impl Handler<AuditQuery> for AuditActor {
+    type Result = Result<Vec<AuditEntry>, String>;
+
+    fn handle(&mut self, msg: AuditQuery, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self
+            .recent
+            .iter()
+            .filter(|e| e.since(&msg.since))
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,284 @@
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::{error, Error, HttpMessage};
+use futures::future::{ok, Ready};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Auth is configured from a toml file, such as:
+//
+//   # What requests without a token may do. Leave out to require a token.
+//   anonymous = "viewer"
+//
+//   [groups]
+//   downstairs = ["lounge", "kitchen", "toilet"]
+//
+//   [[token]]
+//   name = "homeassistant"
+//   secret = "..."
+//   role = "operator"
+//   groups = ["downstairs"]
+//   bulbs = ["deck"]
+//
+// Tokens are given as "Authorization: Bearer <secret>". A token with no bulbs
+// or groups may act on every bulb.
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // Can look at status.
+    Viewer,
+    // Can also set and reset bulbs, report presence, and run the party if allowed
+    // all bulbs.
+    Operator,
+    // Can do anything.
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenConfig {
+    name: String,
+    secret: String,
+    role: Role,
+    #[serde(default)]
+    bulbs: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    anonymous: Option<Role>,
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    #[serde(default, rename = "token")]
+    tokens: Vec<TokenConfig>,
+}
+
+impl AuthConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {} -> {}", path.display(), e))?;
+        let config: AuthConfig = toml::from_str(&data)
+            .map_err(|e| format!("Invalid auth config {} -> {}", path.display(), e))?;
+
+        for t in config.tokens.iter() {
+            if let Some(g) = t.groups.iter().find(|g| !config.groups.contains_key(*g)) {
+                return Err(format!("Token {} refers to unknown group {}", t.name, g));
+            }
+        }
+        Ok(config)
+    }
+
+    fn identify(&self, secret: Option<&str>) -> Result<AuthIdentity, Error> {
+        let secret = match secret {
+            Some(s) => s,
+            None => {
+                return self
+                    .anonymous
+                    .map(|role| AuthIdentity {
+                        name: "anonymous".to_string(),
+                        role,
+                        bulbs: None,
+                    })
+                    .ok_or_else(|| error::ErrorUnauthorized("Token required"))
+            }
+        };
+
+        let t = self
+            .tokens
+            .iter()
+            .find(|t| constant_time_eq(t.secret.as_bytes(), secret.as_bytes()))
+            .ok_or_else(|| error::ErrorUnauthorized("Invalid token"))?;
+
+        let bulbs = if t.bulbs.is_empty() && t.groups.is_empty() {
+            None
+        } else {
+            let mut bulbs: HashSet<String> = t.bulbs.iter().cloned().collect();
+            t.groups
+                .iter()
+                .filter_map(|g| self.groups.get(g))
+                .for_each(|members| bulbs.extend(members.iter().cloned()));
+            Some(bulbs)
+        };
+
+        Ok(AuthIdentity {
+            name: t.name.clone(),
+            role: t.role,
+            bulbs,
+        })
+    }
+}
+
+// Don't leak how much of a secret matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Who made a request. Handlers can find this in the request extensions.
+#[derive(Debug, Clone)]
+pub struct AuthIdentity {
+    pub name: String,
+    pub role: Role,
+    // None means every bulb.
+    bulbs: Option<HashSet<String>>,
+}
+
+impl AuthIdentity {
+    /// Whether this token covers the bulb, to see it or control it.
+    pub fn may_use(&self, bulb: &str) -> bool {
+        self.role == Role::Admin
+            || self
+                .bulbs
+                .as_ref()
+                .map(|b| b.contains(bulb))
+                .unwrap_or(true)
+    }
+
+    fn all_bulbs(&self) -> bool {
+        self.role == Role::Admin || self.bulbs.is_none()
+    }
+}
+
+// What a request is trying to do, worked out from the path since app level
+// middleware runs before routing.
+enum Operation<'a> {
+    Public,
+    View(Option<&'a str>),
+    Control(&'a str),
+    Party,
+    Presence,
+    Other,
+}
+
+fn classify<'a>(method: &Method, path: &'a str) -> Operation<'a> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let read = *method == Method::GET || *method == Method::HEAD;
+
+    match (read, parts.as_slice()) {
+        (true, [""]) | (true, ["static", ..]) | (true, ["pkg", ..]) => Operation::Public,
+        (true, ["manual", name]) => Operation::View(Some(*name)),
+        // Who did what, including their address, is for admins.
+        (true, ["api", "events"]) => Operation::Other,
+        (true, _) => Operation::View(None),
+        (false, ["manual", name])
+        | (false, ["manual", name, "reset"])
+        | (false, ["clean", name, _]) => Operation::Control(name),
+        (false, ["party", _]) => Operation::Party,
+        (false, ["presence", _]) => Operation::Presence,
+        (false, _) => Operation::Other,
+    }
+}
+
+fn authorise(id: &AuthIdentity, op: &Operation) -> Result<(), Error> {
+    let allowed = match op {
+        Operation::Public => true,
+        Operation::View(None) => id.role >= Role::Viewer,
+        Operation::View(Some(bulb)) => id.role >= Role::Viewer && id.may_use(bulb),
+        Operation::Control(bulb) => id.role >= Role::Operator && id.may_use(bulb),
+        Operation::Party => id.role >= Role::Operator && id.all_bulbs(),
+        // Sensors are often given a token of their own, which needn't cover bulbs.
+        Operation::Presence => id.role >= Role::Operator,
+        Operation::Other => id.role >= Role::Admin,
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(error::ErrorForbidden(format!(
+            "{} is not permitted to do this",
+            id.name
+        )))
+    }
+}
+
+/// Auth middleware. With no config every request is allowed, which is how this
+/// has always behaved.
+pub struct Auth {
+    config: Option<Arc<AuthConfig>>,
+}
+
+impl Auth {
+    pub fn new(config: Option<Arc<AuthConfig>>) -> Self {
+        Auth { config }
+    }
+}
+
+impl<S, B> Transform<S> for Auth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthMiddleware {
+            service,
+            config: self.config.clone(),
+        })
+    }
+}
+
+pub struct AuthMiddleware<S> {
+    service: S,
+    config: Option<Arc<AuthConfig>>,
+}
+
+impl<S, B> Service for AuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if let Some(config) = &self.config {
+            let secret = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(|v| v.trim());
+
+            let checked = config
+                .identify(secret)
+                .and_then(|id| authorise(&id, &classify(req.method(), req.path())).map(|_| id));
+
+            match checked {
+                Ok(id) => {
+                    debug!(token = %id.name, "Authorised");
+                    req.extensions_mut().insert(id);
+                }
+                Err(e) => {
+                    info!("Denied -> {}", e);
+                    return Box::pin(async move { Err(e) });
+                }
+            }
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(fut)
+    }
+}
@@ -0,0 +1,73 @@
+extern crate lifx_ctl;
+extern crate structopt;
+
+use lifx_ctl::proto::TileInfo;
+use lifx_ctl::sim::{SimBulb, SimConfig};
+use lifx_ctl::srv::{format_target, parse_target};
+use std::net::SocketAddr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+// Run a pretend bulb, so the server can be tried out without any hardware:
+//
+//   lifx_sim --bind 127.0.0.1:56700 --loss 0.1 --latency 50
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "lifx_sim", about = "A simulated lifx bulb")]
+struct SimOpt {
+    #[structopt(long = "bind", default_value = "127.0.0.1:56700")]
+    bind: SocketAddr,
+    #[structopt(long = "label", default_value = "sim")]
+    label: String,
+    /// The mac to answer as, such as d0:73:d5:00:00:01.
+    #[structopt(long = "target", default_value = "d0:73:d5:00:00:01", parse(try_from_str = parse_target))]
+    target: [u8; 6],
+    /// The lifx product id to report.
+    #[structopt(long = "product", default_value = "27")]
+    product: u32,
+    /// Chance of dropping each packet, from 0 to 1.
+    #[structopt(long = "loss", default_value = "0")]
+    loss: f32,
+    /// Milliseconds to wait before answering.
+    #[structopt(long = "latency", default_value = "0")]
+    latency: u64,
+    /// How many zones, to be a strip such as product 32.
+    #[structopt(long = "zones", default_value = "0")]
+    zones: usize,
+    /// How many 8x8 tiles, to be a Tile such as product 55.
+    #[structopt(long = "tiles", default_value = "0")]
+    tiles: usize,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let opt = SimOpt::from_args();
+
+    let config = SimConfig {
+        label: opt.label,
+        target: opt.target,
+        product: opt.product,
+        loss: opt.loss,
+        latency: Duration::from_millis(opt.latency),
+        zones: opt.zones,
+        tiles: vec![
+            TileInfo {
+                width: 8,
+                height: 8,
+            };
+            opt.tiles
+        ],
+        ..Default::default()
+    };
+    let target = format_target(&config.target);
+    match SimBulb::spawn(opt.bind, config) {
+        Ok(sim) => {
+            println!("Simulating {} on {}", target, sim.addr());
+            sim.join();
+        }
+        Err(e) => {
+            eprintln!("Unable to bind {} -> {}", opt.bind, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::plans::{CircadianBounds, CleanSchedule, LightPlan};
+use crate::products;
+use crate::srv::{parse_target, LightBulb};
+use lifx_core::HSBK;
+use serde_derive::Deserialize;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+// Bulbs are configured from a toml file, such as:
+//
+//   [[bulb]]
+//   name = "toilet"
+//   addr = "172.24.18.13"
+//   target = "d0:73:d5:01:02:03"
+//   product = 90
+//   plan = "RedshiftToilet"
+//   party = "PartyHardToilet"
+//   room = "toilet"
+//   clean = { hour = 3, minute = 0, duration = 7200 }
+//
+//   [[bulb]]
+//   name = "strip"
+//   addr = "172.24.18.30"
+//   plan = "Gradient"
+//   gradient = [
+//     { hue = 0, saturation = 65535, brightness = 65535, kelvin = 3500 },
+//     { hue = 43690, saturation = 65535, brightness = 65535, kelvin = 3500 },
+//   ]
+//
+//   [[bulb]]
+//   name = "bedroom"
+//   addr = "172.24.18.32"
+//   plan = "Circadian"
+//   circadian = { min_kelvin = 2200, max_brightness = 50000 }
+//
+//   [[bulb]]
+//   name = "candle"
+//   addr = "172.24.18.31"
+//   product = 57
+//   plan = "Flame"
+//
+// Only name and addr are needed. Without a target or product they are learnt
+// from the bulb, and the plans default to RedshiftMain and PartyHardMain.
+
+const LIFX_PORT: u16 = 56700;
+
+#[derive(Debug, Deserialize)]
+struct CleanConfig {
+    hour: i32,
+    minute: i32,
+    duration: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ColourConfig {
+    hue: u16,
+    saturation: u16,
+    brightness: u16,
+    kelvin: u16,
+}
+
+impl ColourConfig {
+    fn hsbk(&self) -> HSBK {
+        HSBK {
+            hue: self.hue,
+            saturation: self.saturation,
+            brightness: self.brightness,
+            kelvin: self.kelvin,
+        }
+    }
+}
+
+// Anything left out keeps the default range.
+#[derive(Debug, Deserialize)]
+struct CircadianConfig {
+    #[serde(default)]
+    min_kelvin: Option<u16>,
+    #[serde(default)]
+    max_kelvin: Option<u16>,
+    #[serde(default)]
+    min_brightness: Option<u16>,
+    #[serde(default)]
+    max_brightness: Option<u16>,
+}
+
+impl CircadianConfig {
+    fn bounds(&self) -> CircadianBounds {
+        let d = CircadianBounds::default();
+        CircadianBounds {
+            min_kelvin: self.min_kelvin.unwrap_or(d.min_kelvin),
+            max_kelvin: self.max_kelvin.unwrap_or(d.max_kelvin),
+            min_brightness: self.min_brightness.unwrap_or(d.min_brightness),
+            max_brightness: self.max_brightness.unwrap_or(d.max_brightness),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BulbConfig {
+    name: String,
+    // ip or ip:port.
+    addr: String,
+    #[serde(default)]
+    target: Option<String>,
+    // A lifx product id, as in products.rs.
+    #[serde(default)]
+    product: Option<u32>,
+    #[serde(default)]
+    plan: Option<String>,
+    #[serde(default)]
+    party: Option<String>,
+    #[serde(default)]
+    room: Option<String>,
+    #[serde(default)]
+    clean: Option<CleanConfig>,
+    // The colours at each end, for the Gradient plan.
+    #[serde(default)]
+    gradient: Option<[ColourConfig; 2]>,
+    // The range for the Circadian plan.
+    #[serde(default)]
+    circadian: Option<CircadianConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulbsConfig {
+    #[serde(default, rename = "bulb")]
+    bulbs: Vec<BulbConfig>,
+}
+
+pub fn load(path: &Path) -> Result<Vec<LightBulb>, String> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {} -> {}", path.display(), e))?;
+    parse(&data).map_err(|e| format!("Invalid bulbs config {} -> {}", path.display(), e))
+}
+
+/// The bulbs in a toml bulbs config, ready to register.
+pub fn parse(data: &str) -> Result<Vec<LightBulb>, String> {
+    let config: BulbsConfig = toml::from_str(data).map_err(|e| e.to_string())?;
+
+    let mut names = HashSet::new();
+    let mut targets = HashSet::new();
+    let mut bulbs = Vec::new();
+    for b in config.bulbs {
+        if !names.insert(b.name.clone()) {
+            return Err(format!("Bulb {} is given more than once", b.name));
+        }
+        let addr = b
+            .addr
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                b.addr
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, LIFX_PORT))
+            })
+            .map_err(|_| format!("Invalid address {} for {}", b.addr, b.name))?;
+        let plan = parse_plan(&b, b.plan.as_deref(), LightPlan::RedshiftMain)?;
+        let party = parse_plan(&b, b.party.as_deref(), LightPlan::PartyHardMain)?;
+
+        let mut bulb = LightBulb::new(b.name.clone(), addr, plan, party);
+        if let Some(t) = &b.target {
+            let target = parse_target(t).map_err(|e| format!("{} for {}", e, b.name))?;
+            // Replies are matched to bulbs by target, so two can't share one.
+            if !targets.insert(target) {
+                return Err(format!("Target {} is given to more than one bulb", t));
+            }
+            bulb = bulb.with_target(target);
+        }
+        if let Some(p) = b.product {
+            let info = products::lookup(1, p)
+                .ok_or_else(|| format!("Unknown product {} for {}", p, b.name))?;
+            // Flame draws on tiles. Without a product we can't tell, and the plan
+            // still gives a plain colour for anything else.
+            if !info.matrix && (plan == LightPlan::Flame || party == LightPlan::Flame) {
+                return Err(format!(
+                    "Flame needs a tile or candle, but {} is a {}",
+                    b.name, info.name
+                ));
+            }
+            bulb = bulb.with_product(p);
+        }
+        if let Some(room) = &b.room {
+            bulb = bulb.with_room(room);
+        }
+        if let Some(c) = &b.clean {
+            bulb = bulb.with_clean_schedule(CleanSchedule {
+                hour: c.hour,
+                minute: c.minute,
+                duration: c.duration,
+            });
+        }
+        bulbs.push(bulb);
+    }
+    Ok(bulbs)
+}
+
+fn parse_plan(b: &BulbConfig, name: Option<&str>, default: LightPlan) -> Result<LightPlan, String> {
+    match name {
+        Some(n) if n.eq_ignore_ascii_case("gradient") => match &b.gradient {
+            Some([from, to]) => Ok(LightPlan::Gradient(from.hsbk(), to.hsbk())),
+            None => Err(format!("Gradient for {} needs gradient colours", b.name)),
+        },
+        Some(n) if n.eq_ignore_ascii_case("circadian") => {
+            let bounds = b
+                .circadian
+                .as_ref()
+                .map(CircadianConfig::bounds)
+                .unwrap_or_default();
+            bounds
+                .check()
+                .map_err(|e| format!("{} for {}", e, b.name))?;
+            Ok(LightPlan::Circadian(bounds))
+        }
+        Some(n) => {
+            LightPlan::from_name(n).ok_or_else(|| format!("Unknown plan {} for {}", n, b.name))
+        }
+        None => Ok(default),
+    }
+}
@@ -0,0 +1,295 @@
+use crate::config::{ClientOpt, Command};
+use crate::{ManualReq, ManualStatus, PreviewStep};
+use awc::http::Method;
+use awc::Client;
+
+/// Parse durations like 90s, 45m, 2h or 1h30m into seconds. A bare number is
+/// seconds.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("Duration is empty".to_string());
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+
+    let mut total: u64 = 0;
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let n: u64 = num.parse().map_err(|_| format!("Invalid duration {}", s))?;
+        num.clear();
+        let unit = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("Invalid duration unit {} in {}", c, s)),
+        };
+        total = n
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| format!("Duration {} is too long", s))?;
+    }
+    if !num.is_empty() {
+        return Err(format!("Duration {} is missing a unit", s));
+    }
+    Ok(total)
+}
+
+/// A percentage such as 60%, or a raw 0 - 65535 value.
+pub fn parse_level(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    if s.ends_with('%') {
+        let pct: f32 = s
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| format!("Invalid percentage {}", s))?;
+        if !(0.0..=100.0).contains(&pct) {
+            return Err(format!("Percentage {} is out of range", s));
+        }
+        Ok((65535.0 * pct / 100.0).round() as u16)
+    } else {
+        s.parse().map_err(|_| format!("Invalid level {}", s))
+    }
+}
+
+// Hue and saturation for some common colour names.
+pub fn named_colour(name: &str) -> Option<(u16, u16)> {
+    match name.to_lowercase().as_str() {
+        "white" => Some((0, 0)),
+        "red" => Some((0, 65535)),
+        "orange" => Some((6500, 65535)),
+        "yellow" => Some((10923, 65535)),
+        "green" => Some((16173, 65535)),
+        "cyan" => Some((32768, 65535)),
+        "blue" => Some((43634, 65535)),
+        "purple" => Some((49152, 65535)),
+        "pink" => Some((58275, 65535)),
+        _ => None,
+    }
+}
+
+async fn call(
+    opt: &ClientOpt,
+    method: Method,
+    path: &str,
+    json: Option<&ManualReq>,
+) -> Result<Vec<u8>, String> {
+    let url = format!("{}{}", opt.url.trim_end_matches('/'), path);
+    let mut req = Client::default().request(method, url.as_str());
+    if let Some(t) = &opt.token {
+        req = req.bearer_auth(t);
+    }
+
+    let mut res = match json {
+        Some(j) => req.send_json(j),
+        None => req.send(),
+    }
+    .await
+    .map_err(|e| format!("Request to {} failed -> {}", url, e))?;
+
+    let body = res
+        .body()
+        .await
+        .map_err(|e| format!("Failed to read response from {} -> {}", url, e))?;
+
+    if res.status().is_success() {
+        Ok(body.to_vec())
+    } else {
+        Err(format!(
+            "{} -> {}",
+            res.status(),
+            String::from_utf8_lossy(&body)
+        ))
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    opt: &ClientOpt,
+    path: &str,
+) -> Result<T, String> {
+    let body = call(opt, Method::GET, path, None).await?;
+    serde_json::from_slice(&body).map_err(|e| format!("Invalid response from server -> {}", e))
+}
+
+fn print_table(bulbs: &[ManualStatus]) {
+    let header = [
+        "NAME", "PLAN", "HUE", "SAT", "BRI", "K", "TARGET", "PRODUCT",
+    ];
+    let rows: Vec<Vec<String>> = bulbs
+        .iter()
+        .map(|b| {
+            vec![
+                b.name.clone(),
+                b.plan.clone(),
+                b.hue.to_string(),
+                b.sat.to_string(),
+                b.bri.to_string(),
+                b.k.to_string(),
+                b.target.clone().unwrap_or_else(|| "-".to_string()),
+                b.product.clone().unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].len())
+                .chain(std::iter::once(header[i].len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let print_row = |cols: Vec<&str>| {
+        let line: Vec<String> = cols
+            .iter()
+            .zip(widths.iter())
+            .map(|(c, w)| format!("{:width$}", c, width = w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(header.to_vec());
+    for r in rows.iter() {
+        print_row(r.iter().map(|c| c.as_str()).collect());
+    }
+    for b in bulbs.iter() {
+        if let Some(w) = &b.warning {
+            println!("warning: {} -> {}", b.name, w);
+        }
+    }
+}
+
+fn print_preview(steps: &[PreviewStep]) {
+    println!(
+        "{:8}  {:>5}  {:>5}  {:>5}  {:>5}  {:>8}",
+        "TIME", "HUE", "SAT", "BRI", "K", "DURATION"
+    );
+    for s in steps.iter() {
+        println!(
+            "{:8}  {:>5}  {:>5}  {:>5}  {:>5}  {:>8}",
+            s.time, s.hue, s.sat, s.bri, s.k, s.duration
+        );
+    }
+}
+
+async fn run_command(cmd: Command) -> Result<(), String> {
+    match cmd {
+        Command::List { client } => {
+            let bulbs: Vec<ManualStatus> = get_json(&client, "/api/bulbs").await?;
+            print_table(&bulbs);
+        }
+        Command::Set {
+            client,
+            name,
+            colour,
+            hue,
+            saturation,
+            brightness,
+            kelvin,
+            effect,
+        } => {
+            let current: ManualStatus = get_json(&client, &format!("/manual/{}", name)).await?;
+            let mut req = ManualReq {
+                hue: current.hue,
+                sat: current.sat,
+                bri: current.bri,
+                k: current.k,
+                effect,
+            };
+            if let Some(c) = colour {
+                let (h, s) = named_colour(&c).ok_or_else(|| format!("Unknown colour {}", c))?;
+                req.hue = h;
+                req.sat = s;
+            }
+            if let Some(h) = hue {
+                req.hue = h;
+            }
+            if let Some(s) = saturation {
+                req.sat = parse_level(&s)?;
+            }
+            if let Some(b) = brightness {
+                req.bri = parse_level(&b)?;
+            }
+            if let Some(k) = kelvin {
+                req.k = k;
+            }
+            call(
+                &client,
+                Method::POST,
+                &format!("/manual/{}", name),
+                Some(&req),
+            )
+            .await?;
+
+            let updated: ManualStatus = get_json(&client, &format!("/manual/{}", name)).await?;
+            print_table(&[updated]);
+        }
+        Command::Reset { client, name } => {
+            call(
+                &client,
+                Method::POST,
+                &format!("/manual/{}/reset", name),
+                None,
+            )
+            .await?;
+            println!("{} reset to its default plan", name);
+        }
+        Command::Party {
+            client,
+            action,
+            duration,
+        } => {
+            let path = match (action.as_str(), duration) {
+                ("start", Some(d)) => format!("/party/start?for={}", parse_duration(&d)?),
+                ("start", None) => "/party/start".to_string(),
+                _ => "/party/end".to_string(),
+            };
+            let body = call(&client, Method::POST, &path, None).await?;
+            println!("{}", String::from_utf8_lossy(&body));
+        }
+        Command::Preview {
+            client,
+            plan,
+            from,
+            to,
+            step,
+        } => {
+            let path = format!(
+                "/api/plans/{}/preview?from={}&to={}&step={}",
+                plan,
+                from,
+                to,
+                parse_duration(&step)?
+            );
+            let steps: Vec<PreviewStep> = get_json(&client, &path).await?;
+            print_preview(&steps);
+        }
+        // run hands direct mode off before any server is needed.
+        Command::Direct { .. } => unreachable!("direct mode does not use the server"),
+    }
+    Ok(())
+}
+
+/// Run a client command, returning the exit code.
+pub fn run(cmd: Command) -> i32 {
+    if let Command::Direct { cmd } = cmd {
+        return crate::direct::run(cmd);
+    }
+
+    let mut sys = actix::System::new("lifx_ctl_cli");
+    match sys.block_on(run_command(cmd)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
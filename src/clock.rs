@@ -0,0 +1,77 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::sync::{Arc, Mutex};
+
+// Where the actors get the time and their randomness from. The server uses the
+// system clock in its configured timezone and an entropy seeded rng, and tests
+// swap in a ManualClock and a fixed seed so a whole day can be played back the
+// same way every time.
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Tz>;
+}
+
+/// The wall clock, in the timezone the plans run in.
+pub struct SystemClock {
+    tz: Tz,
+}
+
+impl SystemClock {
+    pub fn new(tz: Tz) -> Self {
+        SystemClock { tz }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Tz> {
+        Utc::now().with_timezone(&self.tz)
+    }
+}
+
+/// A clock that only moves when it is told to.
+pub struct ManualClock {
+    now: Mutex<DateTime<Tz>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Tz>) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Tz>) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now = *now + d;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Tz> {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Before anything has happened, for things that should happen as soon as they
+/// are looked at.
+pub fn epoch() -> DateTime<Tz> {
+    Tz::UTC.timestamp(0, 0)
+}
+
+/// An rng that several actors can draw from.
+pub type SharedRng = Arc<Mutex<dyn RngCore + Send>>;
+
+pub fn system_rng() -> SharedRng {
+    Arc::new(Mutex::new(StdRng::from_entropy()))
+}
+
+/// The same seed gives the same party.
+pub fn seeded_rng(seed: u64) -> SharedRng {
+    Arc::new(Mutex::new(StdRng::seed_from_u64(seed)))
+}
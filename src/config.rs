@@ -0,0 +1,331 @@
+use chrono_tz::Tz;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "lifx_ctl", about = "Control lifx bulbs with light plans")]
+pub struct Opt {
+    #[structopt(flatten)]
+    pub server: Config,
+
+    /// With no command, run the server.
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+/// Where to find a running server.
+#[derive(Debug, StructOpt)]
+pub struct ClientOpt {
+    /// Url of the lifx_ctl server.
+    #[structopt(
+        long = "url",
+        default_value = "http://127.0.0.1:8081",
+        env = "LIFX_CTL_URL"
+    )]
+    pub url: String,
+
+    /// Api token, if the server has an auth file.
+    #[structopt(long = "token", env = "LIFX_CTL_TOKEN")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Show every bulb and its current plan and colour.
+    #[structopt(name = "list")]
+    List {
+        #[structopt(flatten)]
+        client: ClientOpt,
+    },
+    /// Manually set a bulb. Anything not given is kept from its current colour.
+    #[structopt(name = "set")]
+    Set {
+        #[structopt(flatten)]
+        client: ClientOpt,
+        /// The bulb to set.
+        name: String,
+        /// A colour name such as red, blue or white.
+        #[structopt(long = "color", alias = "colour")]
+        colour: Option<String>,
+        /// 0 - 65535
+        #[structopt(long = "hue")]
+        hue: Option<u16>,
+        /// A percentage such as 50%, or 0 - 65535
+        #[structopt(long = "saturation")]
+        saturation: Option<String>,
+        /// A percentage such as 60%, or 0 - 65535
+        #[structopt(long = "brightness")]
+        brightness: Option<String>,
+        #[structopt(long = "kelvin")]
+        kelvin: Option<u16>,
+        /// One of breathe, pulse or strobe.
+        #[structopt(long = "effect")]
+        effect: Option<String>,
+    },
+    /// Put a bulb back on its default plan.
+    #[structopt(name = "reset")]
+    Reset {
+        #[structopt(flatten)]
+        client: ClientOpt,
+        name: String,
+    },
+    /// Talk to bulbs directly over the lan, without the server.
+    #[structopt(name = "direct")]
+    Direct {
+        #[structopt(subcommand)]
+        cmd: DirectCommand,
+    },
+    /// Start or end the party.
+    #[structopt(name = "party")]
+    Party {
+        #[structopt(flatten)]
+        client: ClientOpt,
+        #[structopt(possible_values = &["start", "end"])]
+        action: String,
+        /// How long to party for, such as 2h or 1h30m. Without this the party runs
+        /// until ended.
+        #[structopt(long = "for")]
+        duration: Option<String>,
+    },
+    /// Show what a plan would do over a time of day, without changing any bulbs.
+    #[structopt(name = "preview")]
+    Preview {
+        #[structopt(flatten)]
+        client: ClientOpt,
+        /// A plan such as RedshiftMain.
+        plan: String,
+        /// Time of day to start at, such as 16:00.
+        #[structopt(long = "from", default_value = "00:00")]
+        from: String,
+        /// Time of day to stop at. At or before --from runs into the next day.
+        #[structopt(long = "to", default_value = "00:00")]
+        to: String,
+        /// Time between each step, such as 10m.
+        #[structopt(long = "step", default_value = "15m")]
+        step: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DirectOpt {
+    /// How long to wait for replies, in ms.
+    #[structopt(long = "wait", default_value = "2000")]
+    pub wait: u64,
+
+    /// Print the raw bytes of each packet sent and received.
+    #[structopt(long = "dump")]
+    pub dump: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DirectCommand {
+    /// Broadcast for devices and list those that answer.
+    #[structopt(name = "discover")]
+    Discover {
+        #[structopt(flatten)]
+        opt: DirectOpt,
+        /// Where to send the discovery broadcast.
+        #[structopt(long = "broadcast", default_value = "255.255.255.255")]
+        broadcast: IpAddr,
+    },
+    /// Query a device for its colour, power, version and label.
+    #[structopt(name = "get")]
+    Get {
+        #[structopt(flatten)]
+        opt: DirectOpt,
+        /// ip or ip:port of the device.
+        addr: String,
+        /// The mac of the device, such as d0:73:d5:01:02:03.
+        #[structopt(long = "target")]
+        target: Option<String>,
+    },
+    /// Set the colour of a device. Anything not given is kept from its current colour.
+    #[structopt(name = "set")]
+    Set {
+        #[structopt(flatten)]
+        opt: DirectOpt,
+        /// ip or ip:port of the device.
+        addr: String,
+        /// The mac of the device, such as d0:73:d5:01:02:03.
+        #[structopt(long = "target")]
+        target: Option<String>,
+        /// A colour name such as red, blue or white.
+        #[structopt(long = "color", alias = "colour")]
+        colour: Option<String>,
+        /// 0 - 65535
+        #[structopt(long = "hue")]
+        hue: Option<u16>,
+        /// A percentage such as 50%, or 0 - 65535
+        #[structopt(long = "saturation")]
+        saturation: Option<String>,
+        /// A percentage such as 60%, or 0 - 65535
+        #[structopt(long = "brightness")]
+        brightness: Option<String>,
+        #[structopt(long = "kelvin")]
+        kelvin: Option<u16>,
+        /// Transition time in ms.
+        #[structopt(long = "duration", default_value = "250")]
+        duration: u32,
+    },
+    /// Turn a device on or off.
+    #[structopt(name = "power")]
+    Power {
+        #[structopt(flatten)]
+        opt: DirectOpt,
+        /// ip or ip:port of the device.
+        addr: String,
+        #[structopt(possible_values = &["on", "off"])]
+        state: String,
+        /// The mac of the device, such as d0:73:d5:01:02:03.
+        #[structopt(long = "target")]
+        target: Option<String>,
+        /// Transition time in ms.
+        #[structopt(long = "duration", default_value = "250")]
+        duration: u32,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Config {
+    /// Address for the http server to listen on. Give more than once, or separate
+    /// with commas, to listen on several addresses.
+    #[structopt(
+        long = "bind",
+        default_value = "[::]:8081",
+        env = "LIFX_CTL_BIND",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub bind: Vec<SocketAddr>,
+
+    /// Local address for the lifx udp socket.
+    #[structopt(
+        long = "lifx-addr",
+        default_value = "0.0.0.0",
+        env = "LIFX_CTL_LIFX_ADDR"
+    )]
+    pub lifx_addr: IpAddr,
+
+    /// Local port for the lifx udp socket. This is the source port of every packet.
+    #[structopt(
+        long = "lifx-port",
+        default_value = "56701",
+        env = "LIFX_CTL_LIFX_PORT"
+    )]
+    pub lifx_port: u16,
+
+    /// Directory of static assets served under /static.
+    #[structopt(
+        long = "static-dir",
+        default_value = "./static",
+        env = "LIFX_CTL_STATIC_DIR",
+        parse(from_os_str)
+    )]
+    pub static_dir: PathBuf,
+
+    /// Directory of the wasm package served under /pkg.
+    #[structopt(
+        long = "pkg-dir",
+        default_value = "./pkg",
+        env = "LIFX_CTL_PKG_DIR",
+        parse(from_os_str)
+    )]
+    pub pkg_dir: PathBuf,
+
+    /// Toml file of the bulbs to control, their plans and where they are. Without
+    /// this the built in bulbs are used.
+    #[structopt(long = "bulbs-file", env = "LIFX_CTL_BULBS_FILE", parse(from_os_str))]
+    pub bulbs_file: Option<PathBuf>,
+
+    /// Toml file of api tokens and their roles. Without this anyone may do anything.
+    #[structopt(long = "auth-file", env = "LIFX_CTL_AUTH_FILE", parse(from_os_str))]
+    pub auth_file: Option<PathBuf>,
+
+    /// PEM certificate chain. With --tls-key, the http server only serves https.
+    /// Send SIGHUP to reload it after renewal.
+    #[structopt(long = "tls-cert", env = "LIFX_CTL_TLS_CERT", parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[structopt(long = "tls-key", env = "LIFX_CTL_TLS_KEY", parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+
+    /// Also listen for plain http here, redirecting everything to https.
+    #[structopt(long = "https-redirect", env = "LIFX_CTL_HTTPS_REDIRECT")]
+    pub https_redirect: Option<SocketAddr>,
+
+    /// Timezone the plans run in, such as Australia/Brisbane. Required to run the
+    /// server, as there is no default. Daylight saving is followed, so plans keep
+    /// to the wall clock.
+    #[structopt(long = "timezone", env = "LIFX_CTL_TIMEZONE")]
+    pub timezone: Option<Tz>,
+
+    /// Mqtt broker to follow room presence on, as host or host:port. Needs the mqtt
+    /// feature.
+    #[structopt(long = "mqtt-broker", env = "LIFX_CTL_MQTT_BROKER")]
+    pub mqtt_broker: Option<String>,
+
+    /// Topic of presence reports, where + is the room.
+    #[structopt(
+        long = "mqtt-topic",
+        default_value = "lifx_ctl/presence/+",
+        env = "LIFX_CTL_MQTT_TOPIC"
+    )]
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub mqtt_topic: String,
+
+    /// Id to connect to the broker with. Defaults to lifx_ctl and a random suffix,
+    /// so two instances don't keep dropping each other.
+    #[structopt(long = "mqtt-client-id", env = "LIFX_CTL_MQTT_CLIENT_ID")]
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub mqtt_client_id: Option<String>,
+
+    /// Url to post a json event to when a plan changes, a party starts or ends, or
+    /// a bulb goes offline. Give more than once for several urls.
+    #[structopt(
+        long = "webhook-url",
+        env = "LIFX_CTL_WEBHOOK_URL",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub webhook_urls: Vec<String>,
+
+    /// Sign each webhook body with this, in the x-lifx-ctl-signature header.
+    #[structopt(long = "webhook-secret", env = "LIFX_CTL_WEBHOOK_SECRET")]
+    pub webhook_secret: Option<String>,
+
+    /// Times to retry a webhook that fails, waiting longer before each.
+    #[structopt(
+        long = "webhook-retries",
+        default_value = "3",
+        env = "LIFX_CTL_WEBHOOK_RETRIES"
+    )]
+    pub webhook_retries: u32,
+
+    /// Json lines file to record who changed what in, which is kept across
+    /// restarts. Without this only the latest changes are kept, in memory.
+    #[structopt(long = "audit-log", env = "LIFX_CTL_AUDIT_LOG", parse(from_os_str))]
+    pub audit_log: Option<PathBuf>,
+
+    /// Log filter, such as info or info,lifx_ctl::srv=debug to turn up one
+    /// module. RUST_LOG takes precedence if set.
+    #[structopt(long = "log-level", default_value = "info", env = "LIFX_CTL_LOG_LEVEL")]
+    pub log_level: String,
+
+    /// How to write logs. Json is one object per line with every span field, for
+    /// feeding to a log collector.
+    #[structopt(
+        long = "log-format",
+        default_value = "text",
+        env = "LIFX_CTL_LOG_FORMAT",
+        possible_values = &["text", "pretty", "json"]
+    )]
+    pub log_format: String,
+}
+
+impl Config {
+    pub fn lifx_bind(&self) -> SocketAddr {
+        SocketAddr::new(self.lifx_addr, self.lifx_port)
+    }
+}
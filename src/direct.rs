@@ -0,0 +1,262 @@
+use crate::cli::{named_colour, parse_level};
+use crate::config::{DirectCommand, DirectOpt};
+use lifx_core::Message;
+use lifx_ctl::proto::LifxMessage;
+use lifx_ctl::srv::{format_target, parse_target, target_to_u64, u64_to_target, LifxPackets};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+// Talk to bulbs straight over udp, without the server. This uses the same packet
+// building as the controller, so it doubles as a way to check the wire format.
+
+const LIFX_PORT: u16 = 56700;
+
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, LIFX_PORT)))
+        .map_err(|_| format!("Invalid address {}", s))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+struct Direct {
+    sock: UdpSocket,
+    packets: LifxPackets,
+    opt: DirectOpt,
+}
+
+impl Direct {
+    fn new(opt: DirectOpt) -> Result<Self, String> {
+        let sock = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|s| s.set_broadcast(true).map(|_| s))
+            .map_err(|e| format!("Unable to bind udp socket -> {}", e))?;
+        // Use our own source, so a running server doesn't mistake these replies for
+        // its own. 0 and 1 have special meanings to the bulbs.
+        Ok(Direct {
+            sock,
+            packets: LifxPackets::new(rand::random::<u32>().max(2)),
+            opt,
+        })
+    }
+
+    fn send(
+        &mut self,
+        addr: SocketAddr,
+        target: Option<u64>,
+        ack: bool,
+        msg: Message,
+    ) -> Result<(), String> {
+        let desc = format!("{:?}", msg);
+        let bytes = self
+            .packets
+            .pack(target, ack, msg)
+            .map_err(|e| format!("Failed to build {} -> {:?}", desc, e))?;
+        if self.opt.dump {
+            println!("> {} {}", addr, hex(&bytes));
+        }
+        println!("> {} {}", addr, desc);
+        self.sock
+            .send_to(&bytes, addr)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to send to {} -> {}", addr, e))
+    }
+
+    // Print replies until we have expect of them, or the wait runs out. Returns
+    // what was received.
+    fn recv(&self, expect: Option<usize>) -> Vec<(SocketAddr, u64, LifxMessage)> {
+        self.recv_until(|replies| expect.map(|e| replies.len() >= e).unwrap_or(false))
+    }
+
+    // Wait for the device to acknowledge the last packet sent. Anything else that
+    // turns up, such as a late reply to an earlier packet, doesn't count.
+    fn recv_ack(&self) -> bool {
+        let seq = self.packets.sequence();
+        let is_ack = |msg: &LifxMessage| match msg {
+            LifxMessage::Core(Message::Acknowledgement { seq: s }) => *s == seq,
+            _ => false,
+        };
+        self.recv_until(|replies| replies.iter().any(|(_, _, msg)| is_ack(msg)))
+            .iter()
+            .any(|(_, _, msg)| is_ack(msg))
+    }
+
+    // Print replies until done is happy with them, or the wait runs out.
+    fn recv_until<F>(&self, done: F) -> Vec<(SocketAddr, u64, LifxMessage)>
+    where
+        F: Fn(&[(SocketAddr, u64, LifxMessage)]) -> bool,
+    {
+        let deadline = Instant::now() + Duration::from_millis(self.opt.wait);
+        let mut buf = [0u8; 1024];
+        let mut replies = Vec::new();
+
+        while !done(&replies) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            if self.sock.set_read_timeout(Some(deadline - now)).is_err() {
+                break;
+            }
+            let (len, addr) = match self.sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                // Timed out
+                Err(_) => break,
+            };
+            if self.opt.dump {
+                println!("< {} {}", addr, hex(&buf[..len]));
+            }
+            match self.packets.unpack(&buf[..len]) {
+                Ok(Some((target, msg))) => {
+                    println!(
+                        "< {} {} {:?}",
+                        addr,
+                        format_target(&u64_to_target(target)),
+                        msg
+                    );
+                    replies.push((addr, target, msg));
+                }
+                Ok(None) => {}
+                Err(e) => println!("< {} invalid packet -> {:?}", addr, e),
+            }
+        }
+        replies
+    }
+}
+
+fn device(addr: &str, target: &Option<String>) -> Result<(SocketAddr, Option<u64>), String> {
+    let addr = parse_addr(addr)?;
+    let target = match target {
+        Some(t) => Some(target_to_u64(&parse_target(t)?)),
+        None => None,
+    };
+    Ok((addr, target))
+}
+
+fn run_command(cmd: DirectCommand) -> Result<(), String> {
+    match cmd {
+        DirectCommand::Discover { opt, broadcast } => {
+            let mut d = Direct::new(opt)?;
+            d.send(
+                SocketAddr::new(broadcast, LIFX_PORT),
+                None,
+                false,
+                Message::GetService,
+            )?;
+            let found = d.recv(None);
+            println!("Found {} devices", found.len());
+        }
+        DirectCommand::Get { opt, addr, target } => {
+            let (addr, target) = device(&addr, &target)?;
+            let mut d = Direct::new(opt)?;
+            let msgs = vec![
+                Message::LightGet,
+                Message::GetPower,
+                Message::GetVersion,
+                Message::GetLabel,
+            ];
+            let expect = msgs.len();
+            for m in msgs {
+                d.send(addr, target, false, m)?;
+            }
+            if d.recv(Some(expect)).len() < expect {
+                return Err("Not every request was answered".to_string());
+            }
+        }
+        DirectCommand::Set {
+            opt,
+            addr,
+            target,
+            colour,
+            hue,
+            saturation,
+            brightness,
+            kelvin,
+            duration,
+        } => {
+            let (addr, target) = device(&addr, &target)?;
+            let mut d = Direct::new(opt)?;
+
+            // Start from what the bulb has now, so only what was asked for changes.
+            d.send(addr, target, false, Message::LightGet)?;
+            let mut colour_now = d
+                .recv(Some(1))
+                .into_iter()
+                .filter_map(|(_, _, msg)| match msg {
+                    LifxMessage::Core(Message::LightState { color, .. }) => Some(color),
+                    _ => None,
+                })
+                .next()
+                .ok_or_else(|| format!("No reply from {}", addr))?;
+
+            if let Some(c) = colour {
+                let (h, s) = named_colour(&c).ok_or_else(|| format!("Unknown colour {}", c))?;
+                colour_now.hue = h;
+                colour_now.saturation = s;
+            }
+            if let Some(h) = hue {
+                colour_now.hue = h;
+            }
+            if let Some(s) = saturation {
+                colour_now.saturation = parse_level(&s)?;
+            }
+            if let Some(b) = brightness {
+                colour_now.brightness = parse_level(&b)?;
+            }
+            if let Some(k) = kelvin {
+                colour_now.kelvin = k;
+            }
+
+            d.send(
+                addr,
+                target,
+                true,
+                Message::LightSetColor {
+                    reserved: 0,
+                    color: colour_now,
+                    duration,
+                },
+            )?;
+            if !d.recv_ack() {
+                return Err(format!("{} did not acknowledge", addr));
+            }
+        }
+        DirectCommand::Power {
+            opt,
+            addr,
+            target,
+            state,
+            duration,
+        } => {
+            let (addr, target) = device(&addr, &target)?;
+            let mut d = Direct::new(opt)?;
+            let level = if state == "on" { 65535 } else { 0 };
+            d.send(
+                addr,
+                target,
+                true,
+                Message::LightSetPower { level, duration },
+            )?;
+            if !d.recv_ack() {
+                return Err(format!("{} did not acknowledge", addr));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run a direct mode command, returning the exit code.
+pub fn run(cmd: DirectCommand) -> i32 {
+    match run_command(cmd) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
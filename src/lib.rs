@@ -0,0 +1,20 @@
+#[macro_use]
+extern crate tracing;
+extern crate actix;
+extern crate chrono;
+extern crate chrono_tz;
+extern crate lifx_core;
+extern crate rand;
+
+pub mod audit;
+pub mod bulbs;
+pub mod clock;
+pub mod plans;
+pub mod presence;
+pub mod products;
+pub mod proto;
+pub mod sim;
+pub mod srv;
+pub mod webhook;
+
+pub use srv::*;
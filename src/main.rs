@@ -1,25 +1,48 @@
 #[macro_use]
-extern crate log;
-#[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate tracing;
 extern crate actix;
 extern crate actix_web;
+extern crate chrono;
+extern crate chrono_tz;
 extern crate futures;
 extern crate lifx_core;
+extern crate lifx_ctl;
 extern crate rand;
-extern crate time;
+extern crate structopt;
 
 use actix::prelude::*;
 use actix_files as fs;
-use actix_web::web::{self, Data, Form, HttpResponse, Json, Path};
-use actix_web::{guard, middleware, App, HttpServer};
+use actix_service::Service;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::web::{self, Data, HttpResponse, Json, Path, Query};
+use actix_web::{guard, App, HttpRequest, HttpServer};
 use askama::Template;
 use lifx_core::HSBK;
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+use structopt::StructOpt;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 
-mod plans;
-mod srv;
-use srv::*;
+mod auth;
+mod cli;
+mod config;
+mod direct;
+#[cfg(feature = "tls")]
+mod tls;
+use config::{Config, Opt};
+use lifx_ctl::audit::{self, AuditActor, AuditQuery};
+use lifx_ctl::bulbs;
+use lifx_ctl::clock;
+use lifx_ctl::plans;
+#[cfg(feature = "mqtt")]
+use lifx_ctl::presence;
+use lifx_ctl::webhook;
+use lifx_ctl::*;
 
 /*
 use sr::{
@@ -28,14 +51,31 @@ use sr::{
 };
 */
 
-pub static APPLICATION_JSON: &'static str = "application/json";
-pub static APPLICATION_FORM: &'static str = "application/x-www-form-urlencoded";
-pub static CONTENT_TYPE: &'static str = "content-type";
+pub static APPLICATION_JSON: &str = "application/json";
+pub static APPLICATION_FORM: &str = "application/x-www-form-urlencoded";
+pub static CONTENT_TYPE: &str = "content-type";
 
 #[derive(Template)]
 #[template(path = "wasm.html")]
 struct WasmTemplate;
 
+#[derive(Template)]
+#[template(path = "preview.html")]
+struct PreviewTemplate {
+    plan: String,
+    rows: Vec<PreviewRow>,
+}
+
+// One bar of the preview chart, with the sizes worked out for the template.
+struct PreviewRow {
+    time: String,
+    bri: u16,
+    k: u16,
+    bri_pct: u32,
+    k_pct: u32,
+    colour: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct ManualStatus {
     name: String,
@@ -44,6 +84,77 @@ struct ManualStatus {
     sat: u16,
     bri: u16,
     k: u16,
+    target: Option<String>,
+    // What the bulb reports about itself, once it has been asked.
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    product: Option<String>,
+    #[serde(default)]
+    firmware: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    location: Option<String>,
+    // The kelvin range the bulb supports.
+    #[serde(default)]
+    min_k: u16,
+    #[serde(default)]
+    max_k: u16,
+    // Set when the plan asks for something the bulb can't do.
+    #[serde(default)]
+    warning: Option<String>,
+    // How many zones a strip has, or 0 for everything else.
+    #[serde(default)]
+    zones: usize,
+    // How many tiles a matrix device has, or 0 for everything else.
+    #[serde(default)]
+    tiles: usize,
+    // Infrared level of a night vision bulb.
+    #[serde(default)]
+    infrared: Option<u16>,
+    // Seconds left of a HEV clean cycle, and how the last one went.
+    #[serde(default)]
+    clean_remaining: Option<u32>,
+    #[serde(default)]
+    clean_result: Option<String>,
+    // The room the bulb follows presence in, and whether anyone is there.
+    #[serde(default)]
+    room: Option<String>,
+    #[serde(default)]
+    occupied: Option<bool>,
+}
+
+impl From<LightBulbStatus> for ManualStatus {
+    fn from(s: LightBulbStatus) -> Self {
+        ManualStatus {
+            name: s.name,
+            plan: s.plan,
+            hue: s.current.hue,
+            sat: s.current.saturation,
+            bri: s.current.brightness,
+            k: s.current.kelvin,
+            target: s.target,
+            label: s.info.label,
+            product: s.product,
+            firmware: s.info.firmware,
+            group: s.info.group,
+            location: s.info.location,
+            min_k: s.min_kelvin,
+            max_k: s.max_kelvin,
+            warning: s.warning,
+            zones: s.zones.len(),
+            tiles: s.tiles.len(),
+            infrared: s.infrared,
+            clean_remaining: s.hev.as_ref().map(|h| h.remaining),
+            clean_result: s
+                .hev
+                .and_then(|h| h.last_result)
+                .map(|r| format!("{:?}", r)),
+            room: s.room,
+            occupied: s.occupied,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -52,9 +163,21 @@ struct ManualReq {
     sat: u16,
     bri: u16,
     k: u16,
+    // One of breathe, pulse or strobe.
+    #[serde(default)]
+    effect: Option<String>,
 }
 
 impl ManualReq {
+    fn effect(&self) -> Result<Option<plans::LightEffectKind>, String> {
+        match &self.effect {
+            Some(e) => plans::LightEffectKind::from_name(e)
+                .map(Some)
+                .ok_or_else(|| format!("Unknown effect {}", e)),
+            None => Ok(None),
+        }
+    }
+
     fn into_hsbk(self) -> HSBK {
         HSBK {
             hue: self.hue,
@@ -66,8 +189,57 @@ impl ManualReq {
 }
 
 struct AppState {
-    _log_addr: actix::Addr<LogActor>,
     lightmanager: actix::Addr<LightManager>,
+    audit: actix::Addr<AuditActor>,
+}
+
+// Who is asking, for the audit log.
+fn http_source(req: &HttpRequest) -> audit::Source {
+    let token = req
+        .extensions()
+        .get::<auth::AuthIdentity>()
+        .map(|id| id.name.clone());
+    audit::Source::from_http(req, token)
+}
+
+// Each request runs in its own span, so whatever is logged while serving it
+// carries the method, path and client. The token is only known once auth has
+// run, so it is filled in on the way out.
+fn trace_request<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let span = info_span!(
+        "http",
+        method = %req.method(),
+        path = %req.path(),
+        client = %req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default(),
+        token = tracing::field::Empty
+    );
+    let start = Instant::now();
+    let fut = {
+        let _enter = span.enter();
+        srv.call(req)
+    };
+    let outer = span.clone();
+    async move {
+        let res = fut.await;
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        match &res {
+            Ok(res) => {
+                if let Some(id) = res.request().extensions().get::<auth::AuthIdentity>() {
+                    outer.record("token", id.name.as_str());
+                }
+                info!(status = res.status().as_u16(), elapsed_ms, "Served");
+            }
+            Err(e) => warn!(elapsed_ms, "Failed -> {}", e),
+        }
+        res
+    }
+    .instrument(span)
 }
 
 async fn wasm_view() -> HttpResponse {
@@ -84,16 +256,83 @@ async fn status_view() -> HttpResponse {
     HttpResponse::Ok().body("Ok")
 }
 
-async fn party_start_view(state: Data<AppState>) -> HttpResponse {
-    let _ = state.lightmanager.send(LightManagerPlanStartParty).await;
-    HttpResponse::Ok().body("Party Started!!")
+// The longest party that can be asked for, a week.
+const PARTY_MAX_SECS: i64 = 7 * 86400;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PartyReq {
+    // Seconds until the party ends by itself.
+    #[serde(rename = "for")]
+    duration: Option<i64>,
 }
 
-async fn party_end_view(state: Data<AppState>) -> HttpResponse {
-    let _ = state.lightmanager.send(LightManagerPlanEndParty).await;
+async fn party_start_view(
+    (state, http, req): (Data<AppState>, HttpRequest, Query<PartyReq>),
+) -> HttpResponse {
+    if let Some(d) = req.duration {
+        if d <= 0 || d > PARTY_MAX_SECS {
+            return HttpResponse::BadRequest().body(format!(
+                "Party length {}s is not between 1 and {}s",
+                d, PARTY_MAX_SECS
+            ));
+        }
+    }
+    let msg = LightManagerPlanStartParty {
+        duration: req.duration.map(chrono::Duration::seconds),
+        source: http_source(&http),
+    };
+    let _ = state.lightmanager.send(msg).await;
+    match req.duration {
+        Some(d) => HttpResponse::Ok().body(format!("Party Started for {}s!!", d)),
+        None => HttpResponse::Ok().body("Party Started!!"),
+    }
+}
+
+async fn party_end_view((state, http): (Data<AppState>, HttpRequest)) -> HttpResponse {
+    let msg = LightManagerPlanEndParty {
+        source: http_source(&http),
+    };
+    let _ = state.lightmanager.send(msg).await;
     HttpResponse::Ok().body("Party Over :(")
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct PresenceReq {
+    occupied: bool,
+}
+
+async fn presence_post(
+    (state, http, room, req): (Data<AppState>, HttpRequest, Path<String>, Json<PresenceReq>),
+) -> HttpResponse {
+    let msg = LightManagerPresence {
+        room: room.into_inner(),
+        occupied: req.occupied,
+        source: http_source(&http),
+    };
+    match state.lightmanager.send(msg).await {
+        Ok(()) => HttpResponse::Ok().body("Presence -> Ok"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Presence -> {:?}", e)),
+    }
+}
+
+async fn bulbs_view((state, http): (Data<AppState>, HttpRequest)) -> HttpResponse {
+    match state.lightmanager.send(LightManagerStatus).await {
+        Ok(Ok(list)) => {
+            // A token limited to some bulbs only sees those.
+            let id = http.extensions().get::<auth::AuthIdentity>().cloned();
+            let r: Vec<ManualStatus> = list
+                .into_iter()
+                .filter(|b| id.as_ref().map(|id| id.may_use(&b.name)).unwrap_or(true))
+                .map(ManualStatus::from)
+                .collect();
+            HttpResponse::Ok().json(r)
+        }
+        _ => HttpResponse::InternalServerError()
+            .content_type("text/html")
+            .body("manager status"),
+    }
+}
+
 async fn manual_view((state, name): (Data<AppState>, Path<String>)) -> HttpResponse {
     let r = state
         .lightmanager
@@ -102,67 +341,231 @@ async fn manual_view((state, name): (Data<AppState>, Path<String>)) -> HttpRespo
         })
         .await;
     match r {
-        Ok(Some(s)) => {
-            let r = ManualStatus {
-                name: s.name,
-                plan: s.plan,
-                hue: s.current.hue,
-                sat: s.current.saturation,
-                bri: s.current.brightness,
-                k: s.current.kelvin,
-            };
-            HttpResponse::Ok().json(r)
-        }
-        _ => {
-            HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body("manager status")
-        }
+        Ok(Some(s)) => HttpResponse::Ok().json(ManualStatus::from(s)),
+        _ => HttpResponse::InternalServerError()
+            .content_type("text/html")
+            .body("manager status"),
     }
 }
 
-async fn manual_post_reset((state, name): (Data<AppState>, Path<String>)) -> HttpResponse {
+async fn manual_post_reset(
+    (state, http, name): (Data<AppState>, HttpRequest, Path<String>),
+) -> HttpResponse {
     let _ = state
         .lightmanager
         .send(LightManagerBulbReset {
             name: name.into_inner(),
+            source: http_source(&http),
         })
         .await;
     HttpResponse::Ok().body("Bulb Reset")
 }
 
-async fn manual_post_generic(state: Data<AppState>, name: String, req: ManualReq) -> HttpResponse {
+async fn manual_post_generic(
+    state: Data<AppState>,
+    http: HttpRequest,
+    name: String,
+    req: ManualReq,
+) -> HttpResponse {
+    let effect = match req.effect() {
+        Ok(e) => e,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
     let msg = LightManagerBulbManual {
         name,
         hsbk: req.into_hsbk(),
+        effect,
+        source: http_source(&http),
     };
-    let r = state.lightmanager.send(msg).await;
-    HttpResponse::Ok().body(format!("Status -> {:?}", r))
+    bulb_result(state.lightmanager.send(msg).await)
 }
 
-async fn manual_post_form(
-    (state, name, req): (Data<AppState>, Path<String>, Form<ManualReq>),
-) -> HttpResponse {
-    manual_post_generic(state, name.into_inner(), req.into_inner()).await
+fn bulb_result(r: Result<Result<(), BulbError>, actix::MailboxError>) -> HttpResponse {
+    match r {
+        Ok(Ok(())) => HttpResponse::Ok().body("Status -> Ok"),
+        Ok(Err(BulbError::NotFound)) => HttpResponse::NotFound().body("No such bulb"),
+        Ok(Err(BulbError::Unsupported(e))) => HttpResponse::BadRequest().body(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Status -> {:?}", e)),
+    }
 }
 
-async fn manual_post_json(
-    (state, name, req): (Data<AppState>, Path<String>, Json<ManualReq>),
+#[derive(Clone, Debug, Deserialize)]
+struct PreviewReq {
+    // Times of day such as 18:00. Both default to midnight, which is a whole day.
+    from: Option<String>,
+    to: Option<String>,
+    // Seconds between each step.
+    step: Option<i64>,
+}
+
+// What a plan does at one time of day.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PreviewStep {
+    time: String,
+    hue: u16,
+    sat: u16,
+    bri: u16,
+    k: u16,
+    duration: u32,
+    #[serde(default)]
+    infrared: Option<u16>,
+}
+
+fn plan_preview(plan: &str, req: &PreviewReq) -> Result<Vec<PreviewStep>, String> {
+    let plan = plans::LightPlan::from_name(plan).ok_or_else(|| format!("Unknown plan {}", plan))?;
+    let from = plans::preview_time(req.from.as_deref().unwrap_or("00:00"))?;
+    let to = match &req.to {
+        Some(t) => plans::preview_time(t)?,
+        None => from,
+    };
+    let step = req.step.unwrap_or(900);
+    if step <= 0 {
+        return Err(format!("Invalid step {}", step));
+    }
+
+    Ok(plan
+        .preview(from, to, chrono::Duration::seconds(step))
+        .into_iter()
+        .map(|p| PreviewStep {
+            time: p.at.format("%H:%M:%S").to_string(),
+            hue: p.shift.colour.hue,
+            sat: p.shift.colour.saturation,
+            bri: p.shift.colour.brightness,
+            k: p.shift.colour.kelvin,
+            duration: p.shift.duration,
+            infrared: p.shift.infrared,
+        })
+        .collect())
+}
+
+async fn plan_preview_view((plan, req): (Path<String>, Query<PreviewReq>)) -> HttpResponse {
+    match plan_preview(&plan, &req) {
+        Ok(steps) => HttpResponse::Ok().json(steps),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+// Scale kelvin over the range bulbs can do, for the chart.
+const CHART_MIN_K: u32 = 1500;
+const CHART_MAX_K: u32 = 9000;
+
+async fn plan_chart_view((plan, req): (Path<String>, Query<PreviewReq>)) -> HttpResponse {
+    let steps = match plan_preview(&plan, &req) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let rows = steps
+        .into_iter()
+        .map(|s| {
+            let k = (s.k as u32).clamp(CHART_MIN_K, CHART_MAX_K);
+            let sat = s.sat as u32 * 100 / 65535;
+            PreviewRow {
+                bri_pct: s.bri as u32 * 100 / 65535,
+                k_pct: (k - CHART_MIN_K) * 100 / (CHART_MAX_K - CHART_MIN_K),
+                // Unsaturated colours show as white rather than grey.
+                colour: format!(
+                    "hsl({}, {}%, {}%)",
+                    s.hue as u32 * 360 / 65535,
+                    sat,
+                    100 - sat / 2
+                ),
+                time: s.time,
+                bri: s.bri,
+                k: s.k,
+            }
+        })
+        .collect();
+
+    let t = PreviewTemplate {
+        plan: plan.into_inner(),
+        rows,
+    };
+    match t.render() {
+        Ok(s) => HttpResponse::Ok().content_type("text/html").body(s),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("text/html")
+            .body(format!("{:?}", e)),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct CleanReq {
+    // Seconds to clean for. Left out uses the bulb's default.
+    #[serde(rename = "for")]
+    duration: Option<u32>,
+}
+
+async fn clean_start_view(
+    (state, http, name, req): (Data<AppState>, HttpRequest, Path<String>, Query<CleanReq>),
 ) -> HttpResponse {
-    manual_post_generic(state, name.into_inner(), req.into_inner()).await
+    let msg = LightManagerBulbClean {
+        name: name.into_inner(),
+        duration: Some(req.duration.unwrap_or(0)),
+        source: http_source(&http),
+    };
+    bulb_result(state.lightmanager.send(msg).await)
 }
 
-fn main() {
-    let sys = actix::System::new("lifx_ctl");
+async fn clean_stop_view(
+    (state, http, name): (Data<AppState>, HttpRequest, Path<String>),
+) -> HttpResponse {
+    let msg = LightManagerBulbClean {
+        name: name.into_inner(),
+        duration: None,
+        source: http_source(&http),
+    };
+    bulb_result(state.lightmanager.send(msg).await)
+}
 
-    let logactor_addr = LogActor {}.start();
+async fn manual_post_json(
+    (state, http, name, req): (Data<AppState>, HttpRequest, Path<String>, Json<ManualReq>),
+) -> HttpResponse {
+    manual_post_generic(state, http, name.into_inner(), req.into_inner()).await
+}
 
-    let lifx_addr = LifxController::new(logactor_addr.clone()).start();
+#[derive(Clone, Debug, Deserialize)]
+struct EventsReq {
+    // An rfc3339 time, such as 2020-01-01T02:00:00+10:00.
+    since: Option<String>,
+}
 
-    let lm = LightManager::new(logactor_addr.clone(), lifx_addr.clone()).start();
+async fn events_view((state, req): (Data<AppState>, Query<EventsReq>)) -> HttpResponse {
+    let since = match req
+        .since
+        .as_ref()
+        .map(|s| chrono::DateTime::parse_from_rfc3339(s))
+    {
+        Some(Ok(t)) => Some(t),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(format!("Invalid since -> {}", e)),
+        None => None,
+    };
+    match state.audit.send(AuditQuery { since }).await {
+        Ok(Ok(entries)) => HttpResponse::Ok().json(entries),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Events -> {:?}", e)),
+    }
+}
 
-    // For now, we cheat and pre-reg the bulbs.
+// Send plain http requests over to the https listener.
+async fn https_redirect((req, port): (HttpRequest, Data<u16>)) -> HttpResponse {
+    let conn = req.connection_info();
+    let host = conn.host();
+    // Drop any port from the host, taking care of [v6]:port.
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let location = match *port.get_ref() {
+        443 => format!("https://{}{}", host, req.uri()),
+        p => format!("https://{}:{}{}", host, p, req.uri()),
+    };
+    HttpResponse::PermanentRedirect()
+        .header("location", location)
+        .finish()
+}
 
+// The bulbs at home, for when no bulbs file is given.
+fn builtin_bulbs() -> Vec<LightBulb> {
     let bulb_lounge = LightBulb::new(
         "lounge".to_string(),
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 10)), 56700),
@@ -180,7 +583,14 @@ fn main() {
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 13)), 56700),
         plans::LightPlan::RedshiftToilet,
         plans::LightPlan::PartyHardToilet,
-    );
+    )
+    .with_room("toilet")
+    // Disinfect while nobody is there.
+    .with_clean_schedule(plans::CleanSchedule {
+        hour: 3,
+        minute: 0,
+        duration: 7200,
+    });
     let bulb_office = LightBulb::new(
         "office".to_string(),
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 21)), 56700),
@@ -202,34 +612,226 @@ fn main() {
     let bulb_deck = LightBulb::new(
         "deck".to_string(),
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 24)), 56700),
-        plans::LightPlan::RedshiftMain,
-        plans::LightPlan::RedshiftMain,
+        plans::LightPlan::RedshiftDeck,
+        plans::LightPlan::RedshiftDeck,
     );
+    vec![
+        bulb_lounge,
+        bulb_pole,
+        bulb_toilet,
+        bulb_office,
+        bulb_kitchen,
+        bulb_lamp,
+        bulb_deck,
+    ]
+}
 
-    lm.try_send(LightManagerRegister(bulb_lounge)).unwrap();
-    lm.try_send(LightManagerRegister(bulb_pole)).unwrap();
-    lm.try_send(LightManagerRegister(bulb_toilet)).unwrap();
-    lm.try_send(LightManagerRegister(bulb_office)).unwrap();
-    lm.try_send(LightManagerRegister(bulb_kitchen)).unwrap();
-    lm.try_send(LightManagerRegister(bulb_lamp)).unwrap();
-    lm.try_send(LightManagerRegister(bulb_deck)).unwrap();
+fn main() {
+    let opt = Opt::from_args();
 
-    let _int_addr = IntervalActor::new(logactor_addr.clone(), lm.clone()).start();
+    match opt.cmd {
+        Some(cmd) => std::process::exit(cli::run(cmd)),
+        None => serve(opt.server),
+    }
+}
+
+fn serve(config: Config) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format.as_str() {
+        "json" => logs.json().init(),
+        "pretty" => logs.pretty().init(),
+        _ => logs.init(),
+    }
+
+    let auth_config = match &config.auth_file {
+        Some(path) => match auth::AuthConfig::load(path) {
+            Ok(c) => Some(Arc::new(c)),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            warn!("No auth file configured, the api is open to anyone");
+            None
+        }
+    };
 
-    env_logger::init();
+    let bulbs = match &config.bulbs_file {
+        Some(path) => match bulbs::load(path) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => builtin_bulbs(),
+    };
+
+    #[cfg(feature = "tls")]
+    let tls_cert = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls::ReloadableCert::new(cert, key) {
+            Ok(c) => Some(Arc::new(c)),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("Both --tls-cert and --tls-key are needed for tls");
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "tls")]
+    let tls_enabled = tls_cert.is_some();
+
+    #[cfg(not(feature = "tls"))]
+    let tls_enabled = if config.tls_cert.is_some() || config.tls_key.is_some() {
+        error!("lifx_ctl was built without tls, rebuild with --features tls");
+        std::process::exit(1);
+    } else {
+        false
+    };
+
+    #[cfg(not(feature = "mqtt"))]
+    {
+        if config.mqtt_broker.is_some() {
+            error!("lifx_ctl was built without mqtt, rebuild with --features mqtt");
+            std::process::exit(1);
+        }
+    }
+
+    if config.https_redirect.is_some() && !tls_enabled {
+        error!("--https-redirect needs tls to be configured");
+        std::process::exit(1);
+    }
+
+    // Plans follow the wall clock, so guessing the zone would run them at the
+    // wrong hours.
+    let timezone = match config.timezone {
+        Some(tz) => tz,
+        None => {
+            error!("--timezone (or LIFX_CTL_TIMEZONE) is required, such as Australia/Brisbane");
+            std::process::exit(1);
+        }
+    };
+
+    let sys = actix::System::new("lifx_ctl");
+
+    // Pick up renewed certificates without dropping connections.
+    #[cfg(all(feature = "tls", unix))]
+    {
+        if let Some(cert) = tls_cert.clone() {
+            actix::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                let mut hup = match signal(SignalKind::hangup()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Unable to listen for SIGHUP, tls reload disabled -> {}", e);
+                        return;
+                    }
+                };
+                while hup.recv().await.is_some() {
+                    match cert.reload() {
+                        Ok(()) => info!("Reloaded tls certificate"),
+                        Err(e) => error!(
+                            "Failed to reload tls certificate, keeping the old one -> {}",
+                            e
+                        ),
+                    }
+                }
+            });
+        }
+    }
+
+    let lifx_addr = match LifxController::new(config.lifx_bind()) {
+        Ok(c) => c.start(),
+        Err(e) => {
+            error!(
+                "Unable to bind lifx socket to {} -> {}",
+                config.lifx_bind(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+
+    info!("Running plans in {}", timezone);
+    let clock: Arc<dyn clock::Clock> = Arc::new(clock::SystemClock::new(timezone));
+    let audit = match &config.audit_log {
+        Some(path) => match AuditActor::new().with_file(path) {
+            Ok(a) => a,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => AuditActor::new(),
+    }
+    .start();
+    let mut lm = LightManager::new(lifx_addr.clone())
+        .with_clock(clock.clone())
+        .with_audit(audit.clone());
+    if !config.webhook_urls.is_empty() {
+        let mut webhooks = webhook::WebhookActor::new(config.webhook_urls.clone())
+            .with_retries(config.webhook_retries, std::time::Duration::from_secs(1));
+        match &config.webhook_secret {
+            Some(secret) => webhooks = webhooks.with_secret(secret.clone()),
+            None => warn!("No webhook secret configured, webhooks are not signed"),
+        }
+        lm = lm.with_webhooks(webhooks.start());
+    }
+    let lm = lm.start();
+
+    for b in bulbs {
+        lm.try_send(LightManagerRegister(b)).unwrap();
+    }
+
+    #[cfg(feature = "mqtt")]
+    {
+        if let Some(broker) = &config.mqtt_broker {
+            let client_id = presence::client_id(config.mqtt_client_id.as_deref());
+            if let Err(e) = presence::start_mqtt(broker, &client_id, &config.mqtt_topic, lm.clone())
+            {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let _int_addr = IntervalActor::new(lm.clone()).with_clock(clock).start();
+
+    for dir in &[&config.static_dir, &config.pkg_dir] {
+        if !dir.is_dir() {
+            warn!("Asset directory {} does not exist", dir.display());
+        }
+    }
+    let static_dir = config.static_dir.clone();
+    let pkg_dir = config.pkg_dir.clone();
 
     let server = HttpServer::new(move || {
         App::new()
             .data(AppState {
-                _log_addr: logactor_addr.clone(),
                 lightmanager: lm.clone(),
+                audit: audit.clone(),
             })
-            .wrap(middleware::Logger::default())
-            .service(fs::Files::new("/static", "./static"))
-            .service(fs::Files::new("/pkg", "./pkg"))
+            .wrap(auth::Auth::new(auth_config.clone()))
+            .wrap_fn(trace_request)
+            .service(fs::Files::new("/static", &static_dir))
+            .service(fs::Files::new("/pkg", &pkg_dir))
             .route("", web::get().to(wasm_view))
             .route("/", web::get().to(wasm_view))
             .route("/status", web::get().to(status_view))
+            .route("/api/bulbs", web::get().to(bulbs_view))
+            .route("/api/events", web::get().to(events_view))
+            .route(
+                "/api/plans/{plan}/preview",
+                web::get().to(plan_preview_view),
+            )
+            .route("/plans/{plan}/preview", web::get().to(plan_chart_view))
             .route("/party/start", web::post().to(party_start_view))
             .route("/party/end", web::post().to(party_end_view))
             .route("/manual/{name}", web::get().to(manual_view))
@@ -240,8 +842,48 @@ fn main() {
                     .guard(guard::Header(CONTENT_TYPE, APPLICATION_JSON)),
             )
             .route("/manual/{name}/reset", web::post().to(manual_post_reset))
+            .route("/clean/{name}/start", web::post().to(clean_start_view))
+            .route("/clean/{name}/stop", web::post().to(clean_stop_view))
+            .route("/presence/{room}", web::post().to(presence_post))
     });
-    server.bind("[::]:8081").unwrap().run();
+    let server = config.bind.iter().try_fold(server, |server, addr| {
+        #[cfg(feature = "tls")]
+        let bound = match &tls_cert {
+            Some(cert) => server.bind_rustls(addr, tls::server_config(cert.clone())),
+            None => server.bind(addr),
+        };
+        #[cfg(not(feature = "tls"))]
+        let bound = server.bind(addr);
+        bound.map_err(|e| (*addr, e))
+    });
+    match server {
+        Ok(server) => {
+            server.run();
+        }
+        Err((addr, e)) => {
+            error!("Unable to bind http server to {} -> {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(redirect) = config.https_redirect {
+        let https_port = config.bind.first().map(|a| a.port()).unwrap_or(443);
+        let server = HttpServer::new(move || {
+            App::new()
+                .data(https_port)
+                .wrap_fn(trace_request)
+                .default_service(web::route().to(https_redirect))
+        });
+        match server.bind(redirect) {
+            Ok(server) => {
+                server.run();
+            }
+            Err(e) => {
+                error!("Unable to bind https redirect to {} -> {}", redirect, e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     info!("Starting event server ...");
 
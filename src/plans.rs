@@ -1,14 +1,16 @@
 extern crate lifx_core;
 use lifx_core::HSBK;
 
-extern crate time;
+use chrono::{DateTime, Duration, LocalResult, NaiveDate, NaiveTime, TimeZone, Timelike};
+use chrono_tz::Tz;
+use std::fmt;
 
 extern crate rand;
-use rand::{seq::IteratorRandom, thread_rng};
+use rand::{seq::IteratorRandom, thread_rng, Rng, RngCore};
 
 // LightPlans?
 
-static party_colours: [HSBK; 5] = [
+static PARTY_COLOURS: [HSBK; 5] = [
     // Blue
     HSBK {
         hue: 43634,
@@ -46,94 +48,564 @@ static party_colours: [HSBK; 5] = [
     },
 ];
 
-// struct redshift_main
-fn rshift_calc(vmax: u16, vmin: u16, hour: i32, hlow: i32, hhigh: i32, min: i32) -> u16 {
-    let h_rem = hhigh - hour;
-    // Number of minutes in the window
-    let min_tot = ((hhigh - hlow) * 60) as f32;
-    // Number of minutes left in the window
-    let min_rem = ((h_rem * 60) - min) as f32;
+/// Part of the day by the wall clock, from start until just before end. An end
+/// at or before start runs past midnight into the next day.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimeWindow {
+    // Minutes past midnight.
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    pub const fn new(start: (u32, u32), end: (u32, u32)) -> Self {
+        TimeWindow {
+            start: start.0 * 60 + start.1,
+            end: end.0 * 60 + end.1,
+        }
+    }
+
+    pub const fn hours(start: u32, end: u32) -> Self {
+        TimeWindow::new((start, 0), (end, 0))
+    }
+
+    // When the window around ts opened and when it closes. These are found from
+    // the timezone, so on daylight saving days the window is an hour longer or
+    // shorter rather than jumping.
+    fn bounds(&self, ts: &DateTime<Tz>) -> Option<(DateTime<Tz>, DateTime<Tz>)> {
+        let tz = ts.timezone();
+        let today = ts.naive_local().date();
+        let wraps = self.end <= self.start;
+        // One that runs past midnight may have opened yesterday.
+        [today.pred(), today]
+            .iter()
+            .filter_map(|day| {
+                let open = wall_clock(&tz, *day, self.start, false)?;
+                let close_day = if wraps { day.succ() } else { *day };
+                let close = wall_clock(&tz, close_day, self.end, true)?;
+                Some((open, close))
+            })
+            .find(|(open, close)| open <= ts && ts < close)
+    }
+
+    pub fn contains(&self, ts: &DateTime<Tz>) -> bool {
+        self.bounds(ts).is_some()
+    }
+
+    /// How much of the window is left at ts, from 1 as it opens down towards 0,
+    /// or None outside it. This moves in whole minutes.
+    pub fn remaining(&self, ts: &DateTime<Tz>) -> Option<f32> {
+        let (open, close) = self.bounds(ts)?;
+        let total = (close - open).num_minutes();
+        let left = total - (*ts - open).num_minutes();
+        Some(left as f32 / total as f32)
+    }
+
+    fn edges(&self) -> Vec<u32> {
+        vec![self.start, self.end]
+    }
+}
+
+// When the clock in tz reads minutes past midnight on day. A time skipped by the
+// clocks going forward is when they jump, and one passed twice when they go back
+// is the first or last time round.
+fn wall_clock(tz: &Tz, day: NaiveDate, minutes: u32, last: bool) -> Option<DateTime<Tz>> {
+    let wall = day.and_hms(0, 0, 0) + Duration::minutes(minutes as i64);
+    match tz.from_local_datetime(&wall) {
+        LocalResult::Single(t) => Some(t),
+        LocalResult::Ambiguous(first, second) => Some(if last { second } else { first }),
+        LocalResult::None => (1..=180)
+            .filter_map(|m| {
+                tz.from_local_datetime(&(wall + Duration::minutes(m)))
+                    .earliest()
+            })
+            .next(),
+    }
+}
+
+// The bands of the redshift plans, shared by shift and next_change.
+const MAIN_DAY: TimeWindow = TimeWindow::hours(8, 16);
+const MAIN_EVENING: TimeWindow = TimeWindow::hours(16, 19);
+const MAIN_NIGHT: TimeWindow = TimeWindow::hours(19, 20);
+const MAIN_RAMP: TimeWindow = TimeWindow::hours(16, 20);
+// The kitchen and toilet stay bright for longer, and dim over the evening.
+const LATE_DAY: TimeWindow = TimeWindow::hours(8, 18);
+const LATE_EVENING: TimeWindow = TimeWindow::hours(18, 23);
+// When the deck camera can see without infrared.
+const DECK_DAY: TimeWindow = TimeWindow::hours(8, 20);
 
+// struct redshift_main
+fn rshift_calc(vmax: u16, vmin: u16, remaining: f32) -> u16 {
     let vdiff = (vmax - vmin) as f32;
 
-    let v = (vmin as f32) + (vdiff * (min_rem / min_tot));
+    let v = (vmin as f32) + (vdiff * remaining);
 
     v as u16
 }
 
+// TimeWindow::remaining only moves once a minute, so inside a ramp the output
+// can only move once the next minute starts.
+fn until_next_minute(ts: &DateTime<Tz>) -> Duration {
+    Duration::seconds((60 - ts.second() as i64).max(1))
+}
+
+// How long until the clock next reads minutes past midnight, wrapping to
+// tomorrow if needed. Days around daylight saving changes aren't 24 hours, so
+// this asks the timezone.
+fn until_wall_clock(ts: &DateTime<Tz>, minutes: u32) -> Duration {
+    let tz = ts.timezone();
+    let today = ts.naive_local().date();
+    (0..3)
+        .filter_map(|days| wall_clock(&tz, today + Duration::days(days), minutes, false))
+        .map(|t| t - *ts)
+        .find(|d| *d > Duration::zero())
+        .unwrap_or_else(|| Duration::hours(24))
+}
+
+// Given the bands of a redshift plan, and the part that ramps, work out when the
+// output can next differ.
+fn redshift_next_change(ts: &DateTime<Tz>, bands: &[TimeWindow], ramp: &TimeWindow) -> Duration {
+    if ramp.contains(ts) {
+        until_next_minute(ts)
+    } else {
+        bands
+            .iter()
+            .flat_map(|b| b.edges())
+            .map(|m| until_wall_clock(ts, m))
+            .min()
+            .unwrap_or_else(|| Duration::hours(24))
+    }
+}
+
+/// The waveforms the bulb firmware can render by itself.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightWaveform {
+    Saw,
+    Sine,
+    HalfSine,
+    Triangle,
+    Pulse,
+}
+
+/// A waveform for the bulb to run, swinging between the shift colour and the
+/// effect colour.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LightEffect {
+    pub colour: HSBK,
+    pub waveform: LightWaveform,
+    // ms per cycle
+    pub period: u32,
+    pub cycles: f32,
+    // -32768 to 32767 maps to 0 - 1, which is the duty cycle of a pulse.
+    pub skew_ratio: i16,
+    // Return to the shift colour once the cycles are complete.
+    pub transient: bool,
+    // Only swing the brightness and leave hue/sat/k alone.
+    pub brightness_only: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightEffectKind {
+    Breathe,
+    Pulse,
+    Strobe,
+}
+
+impl LightEffectKind {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "breathe" => Some(LightEffectKind::Breathe),
+            "pulse" => Some(LightEffectKind::Pulse),
+            "strobe" => Some(LightEffectKind::Strobe),
+            _ => None,
+        }
+    }
+}
+
+// Effects are re-sent on each refresh anyway, so just make sure they never run out.
+const EFFECT_CYCLES: f32 = 1_000_000.0;
+
 #[derive(Debug, PartialEq)]
 pub struct LightShift {
     pub colour: HSBK,
     pub duration: u32,
-    pub flicker: bool,
+    pub effect: Option<LightEffect>,
+    // Infrared level for night vision bulbs. None leaves it as it is.
+    pub infrared: Option<u16>,
+}
+
+// Now and then the party toilet light cuts out a few times, like a failing tube.
+// The bulb runs the cuts itself, as a short pulse down to black.
+fn toilet_flicker(rng: &mut dyn RngCore) -> Option<LightEffect> {
+    if rng.gen_range(0, 6) != 0 {
+        return None;
+    }
+    Some(LightEffect {
+        colour: HSBK {
+            hue: 0,
+            saturation: 0,
+            brightness: 0,
+            kelvin: 0,
+        },
+        waveform: LightWaveform::Pulse,
+        period: rng.gen_range(2, 12) * 25,
+        cycles: rng.gen_range(1, 4) as f32,
+        skew_ratio: rng.gen_range(-16384, 16384),
+        transient: true,
+        brightness_only: true,
+    })
+}
+
+// How dark the toilet gets at night, and when nobody has been in for a while.
+const TOILET_NIGHT: HSBK = HSBK {
+    hue: 0,
+    saturation: 0,
+    brightness: 7500,
+    kelvin: 150,
+};
+// How long the toilet waits after someone leaves before going dark.
+const TOILET_VACANT_SECS: i64 = 300;
+
+// Infrared for the deck at night. Kept low so the camera isn't washed out by glare.
+const INFRARED_NIGHT: u16 = 16384;
+
+/// A time of day to run a HEV clean cycle on a LIFX Clean bulb.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CleanSchedule {
+    pub hour: i32,
+    pub minute: i32,
+    // Seconds the cycle runs for.
+    pub duration: u32,
+}
+
+// If we were down at the start time, still clean if we're back within this long.
+const CLEAN_WINDOW_MINS: u32 = 60;
+
+impl CleanSchedule {
+    /// Whether a cycle should start at ts, given when the last one started.
+    pub fn due(&self, ts: &DateTime<Tz>, last: Option<&DateTime<Tz>>) -> bool {
+        let start = (self.hour as u32, self.minute as u32);
+        let end = (start.0, start.1 + CLEAN_WINDOW_MINS);
+        let ran_today = last
+            .map(|l| (*ts - *l) < Duration::hours(12))
+            .unwrap_or(false);
+        TimeWindow::new(start, end).contains(ts) && !ran_today
+    }
+}
+
+/// Colours for each zone of a multizone device, first zone first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightZoneShift {
+    pub zones: Vec<HSBK>,
+    pub duration: u32,
+}
+
+/// Colours for each pixel of a matrix tile, in rows from the top left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightFrame {
+    pub pixels: Vec<HSBK>,
+    pub duration: u32,
+}
+
+// Warm candle colours, from the cool tip of the flame to the hot base.
+const FLAME_TIP: HSBK = HSBK {
+    hue: 0,
+    saturation: 65535,
+    brightness: 6000,
+    kelvin: 2500,
+};
+const FLAME_BASE: HSBK = HSBK {
+    hue: 7000,
+    saturation: 50000,
+    brightness: 52000,
+    kelvin: 2500,
+};
+
+fn blend(a: u16, b: u16, f: f32) -> u16 {
+    (a as f32 + (b as f32 - a as f32) * f).round() as u16
+}
+
+// The colour f of the way from a to b.
+fn blend_hsbk(a: &HSBK, b: &HSBK, f: f32) -> HSBK {
+    HSBK {
+        hue: blend(a.hue, b.hue, f),
+        saturation: blend(a.saturation, b.saturation, f),
+        brightness: blend(a.brightness, b.brightness, f),
+        kelvin: blend(a.kelvin, b.kelvin, f),
+    }
+}
+
+/// The range the Circadian plan moves a bulb through over a day. It is at the
+/// min at night and the max in the middle of the day.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CircadianBounds {
+    pub min_kelvin: u16,
+    pub max_kelvin: u16,
+    pub min_brightness: u16,
+    pub max_brightness: u16,
+}
+
+impl Default for CircadianBounds {
+    fn default() -> Self {
+        CircadianBounds {
+            min_kelvin: 2700,
+            max_kelvin: 5000,
+            min_brightness: 26000,
+            max_brightness: 65535,
+        }
+    }
+}
+
+impl CircadianBounds {
+    /// A range must run from min up to max.
+    pub fn check(&self) -> Result<(), String> {
+        if self.min_kelvin > self.max_kelvin {
+            return Err(format!(
+                "Circadian min_kelvin {} is above max_kelvin {}",
+                self.min_kelvin, self.max_kelvin
+            ));
+        }
+        if self.min_brightness > self.max_brightness {
+            return Err(format!(
+                "Circadian min_brightness {} is above max_brightness {}",
+                self.min_brightness, self.max_brightness
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The Circadian plan is brightest and coolest at 13:00, and dimmest and warmest
+// twelve hours later.
+const CIRCADIAN_PEAK_SECS: f32 = 13.0 * 3600.0;
+const DAY_SECS: f32 = 86400.0;
+// Each step fades over a whole minute, so the bulb never visibly steps.
+const CIRCADIAN_STEP_SECS: i64 = 60;
+
+// How far through the day's swing ts is, from 0 at night to 1 at the peak. This
+// is a cosine of the time of day, so it has no edges anywhere. The hour skipped
+// or repeated by daylight saving falls at night, where it barely moves.
+fn circadian_level(ts: &DateTime<Tz>) -> f32 {
+    let secs = ts.num_seconds_from_midnight() as f32;
+    let angle = 2.0 * std::f32::consts::PI * (secs - CIRCADIAN_PEAK_SECS) / DAY_SECS;
+    0.5 + 0.5 * angle.cos()
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum LightPlan {
     RedshiftMain,
+    // RedshiftMain, plus infrared at night for the camera.
+    RedshiftDeck,
     RedshiftToilet,
     RedshiftKitchen,
     PartyHardMain,
     PartyHardToilet,
     Pause,
     Manual(HSBK),
+    Effect(LightEffectKind, HSBK),
+    // Fade from the first colour to the second along a strip, or down a tile.
+    Gradient(HSBK, HSBK),
+    // A flickering candle flame.
+    Flame,
+    // Kelvin and brightness following the time of day smoothly.
+    Circadian(CircadianBounds),
 }
 
-impl LightPlan {
-    pub fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for LightPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
             LightPlan::RedshiftMain => "RedshiftMain",
+            LightPlan::RedshiftDeck => "RedshiftDeck",
             LightPlan::RedshiftToilet => "RedshiftToilet",
             LightPlan::RedshiftKitchen => "RedshiftKitchen",
             LightPlan::PartyHardMain => "PartyHardMain",
             LightPlan::PartyHardToilet => "PartyHardToilet",
             LightPlan::Pause => "Pause",
             LightPlan::Manual(_) => "Manual",
+            LightPlan::Effect(LightEffectKind::Breathe, _) => "Breathe",
+            LightPlan::Effect(LightEffectKind::Pulse, _) => "Pulse",
+            LightPlan::Effect(LightEffectKind::Strobe, _) => "Strobe",
+            LightPlan::Gradient(_, _) => "Gradient",
+            LightPlan::Flame => "Flame",
+            LightPlan::Circadian(_) => "Circadian",
+        };
+        f.write_str(name)
+    }
+}
+
+impl LightPlan {
+    /// The plans that need nothing more than their name, by the names to_string
+    /// gives. Manual, effects and gradients need colours so aren't found here.
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "redshiftmain" => Some(LightPlan::RedshiftMain),
+            "redshiftdeck" => Some(LightPlan::RedshiftDeck),
+            "redshifttoilet" => Some(LightPlan::RedshiftToilet),
+            "redshiftkitchen" => Some(LightPlan::RedshiftKitchen),
+            "partyhardmain" => Some(LightPlan::PartyHardMain),
+            "partyhardtoilet" => Some(LightPlan::PartyHardToilet),
+            "pause" => Some(LightPlan::Pause),
+            "flame" => Some(LightPlan::Flame),
+            "circadian" => Some(LightPlan::Circadian(CircadianBounds::default())),
+            _ => None,
         }
-        .to_string()
     }
 
-    pub fn shift(&self, ts: time::Tm) -> Option<LightShift> {
-        let hour = ts.tm_hour;
-        let minute = ts.tm_min;
+    /// How long after ts until this plan could return a different shift. The
+    /// manager uses this to avoid re-sending identical colours to the bulbs.
+    pub fn next_change(&self, ts: &DateTime<Tz>) -> Duration {
+        match self {
+            // These bands must match the ones in shift below.
+            LightPlan::RedshiftMain | LightPlan::RedshiftDeck => {
+                redshift_next_change(ts, &[MAIN_DAY, MAIN_EVENING, MAIN_NIGHT], &MAIN_RAMP)
+            }
+            LightPlan::RedshiftKitchen | LightPlan::RedshiftToilet => {
+                redshift_next_change(ts, &[LATE_DAY, LATE_EVENING], &LATE_EVENING)
+            }
+            // Every shift is a new random colour or flicker, so always resend.
+            LightPlan::PartyHardMain | LightPlan::PartyHardToilet | LightPlan::Flame => {
+                Duration::zero()
+            }
+            LightPlan::Circadian(_) => Duration::seconds(CIRCADIAN_STEP_SECS),
+            // Manual only changes when someone changes the plan, and effects are
+            // run by the bulb itself.
+            LightPlan::Manual(_)
+            | LightPlan::Effect(_, _)
+            | LightPlan::Gradient(_, _)
+            | LightPlan::Pause => Duration::hours(24),
+        }
+    }
 
+    /// What to show once the bulb's room has been empty for the given time.
+    /// Plans without this carry on the same whether anyone is there or not.
+    pub fn vacant(&self) -> Option<(Duration, LightShift)> {
         match self {
-            LightPlan::RedshiftMain => {
-                // Helper values
-                let DAY_START = 8;
-                let DAY_END = 16;
-                let EVENING_END = 19;
-                let NIGHT_END = 20;
+            LightPlan::RedshiftToilet => Some((
+                Duration::seconds(TOILET_VACANT_SECS),
+                LightShift {
+                    duration: 4000,
+                    effect: None,
+                    infrared: None,
+                    colour: TOILET_NIGHT,
+                },
+            )),
+            _ => None,
+        }
+    }
 
+    /// What each of count zones should show. Plans with nothing particular for
+    /// strips give None, and the whole device follows shift instead.
+    pub fn shift_zones(&self, ts: DateTime<Tz>, count: usize) -> Option<LightZoneShift> {
+        if count == 0 {
+            return None;
+        }
+        match self {
+            // Scroll bands of the party colours along the strip, a zone a second.
+            LightPlan::PartyHardMain => {
+                let band = (count / PARTY_COLOURS.len()).max(1);
+                let offset = (ts.minute() * 60 + ts.second()) as usize;
+                Some(LightZoneShift {
+                    zones: (0..count)
+                        .map(|i| PARTY_COLOURS[((i + offset) / band) % PARTY_COLOURS.len()])
+                        .collect(),
+                    duration: 1000,
+                })
+            }
+            LightPlan::Gradient(from, to) => {
+                let last = (count - 1).max(1) as f32;
+                Some(LightZoneShift {
+                    zones: (0..count)
+                        .map(|i| blend_hsbk(from, to, i as f32 / last))
+                        .collect(),
+                    duration: 250,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// What each pixel of a width x height tile should show. Plans with nothing
+    /// particular for tiles give None, and the whole device follows shift instead.
+    pub fn shift_frame(&self, ts: DateTime<Tz>, width: usize, height: usize) -> Option<LightFrame> {
+        self.shift_frame_with(ts, width, height, &mut thread_rng())
+    }
+
+    /// As shift_frame, drawing any randomness from rng.
+    pub fn shift_frame_with(
+        &self,
+        _ts: DateTime<Tz>,
+        width: usize,
+        height: usize,
+        rng: &mut dyn RngCore,
+    ) -> Option<LightFrame> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let last_row = (height - 1).max(1) as f32;
+        match self {
+            LightPlan::Gradient(from, to) => Some(LightFrame {
+                pixels: (0..height)
+                    .flat_map(|y| (0..width).map(move |_| y))
+                    .map(|y| blend_hsbk(from, to, y as f32 / last_row))
+                    .collect(),
+                duration: 250,
+            }),
+            // Hotter towards the bottom, with each pixel wavering on its own.
+            LightPlan::Flame => Some(LightFrame {
+                pixels: (0..height)
+                    .flat_map(|y| (0..width).map(move |_| y))
+                    .map(|y| {
+                        let heat = y as f32 / last_row;
+                        let c = blend_hsbk(&FLAME_TIP, &FLAME_BASE, heat);
+                        HSBK {
+                            brightness: blend(0, c.brightness, rng.gen_range(0.6, 1.0)),
+                            ..c
+                        }
+                    })
+                    .collect(),
+                duration: 600,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn shift(&self, ts: DateTime<Tz>) -> Option<LightShift> {
+        self.shift_with(ts, &mut thread_rng())
+    }
+
+    /// As shift, drawing any randomness from rng so a run can be repeated.
+    pub fn shift_with(&self, ts: DateTime<Tz>, rng: &mut dyn RngCore) -> Option<LightShift> {
+        match self {
+            LightPlan::RedshiftMain => {
                 Some(LightShift {
                     duration: 4000,
-                    flicker: false,
-                    colour: if hour >= DAY_START && hour < DAY_END {
+                    effect: None,
+                    infrared: None,
+                    colour: if MAIN_DAY.contains(&ts) {
                         HSBK {
                             hue: 0,
                             saturation: 0,
                             brightness: 65535,
                             kelvin: 4000,
                         }
-                    } else if hour >= DAY_END && hour < EVENING_END {
-                        let bright = rshift_calc(65535, 45000, hour, DAY_END, EVENING_END, minute);
+                    } else if let Some(left) = MAIN_EVENING.remaining(&ts) {
+                        let bright = rshift_calc(65535, 45000, left);
 
                         HSBK {
                             hue: 0,
                             saturation: 0,
-                            brightness: bright as u16,
+                            brightness: bright,
                             kelvin: 4000,
                         }
-                    } else if hour >= EVENING_END && hour < NIGHT_END {
-                        let bright =
-                            rshift_calc(45000, 33000, hour, EVENING_END, NIGHT_END, minute);
-                        let k = rshift_calc(4000, 2750, hour, EVENING_END, NIGHT_END, minute);
+                    } else if let Some(left) = MAIN_NIGHT.remaining(&ts) {
+                        let bright = rshift_calc(45000, 33000, left);
+                        let k = rshift_calc(4000, 2750, left);
 
                         HSBK {
                             hue: 0,
                             saturation: 0,
-                            brightness: bright as u16,
-                            kelvin: k as u16,
+                            brightness: bright,
+                            kelvin: k,
                         }
                     } else {
                         HSBK {
@@ -147,31 +619,28 @@ impl LightPlan {
                 }) // End some
             }
             LightPlan::RedshiftKitchen => {
-                let DAY_START = 8;
-                let DAY_END = 18;
-                let NIGHT_END = 23;
-
                 // This may need an extra stepping perhaps
 
                 Some(LightShift {
                     duration: 4000,
-                    flicker: false,
-                    colour: if hour >= DAY_START && hour < DAY_END {
+                    effect: None,
+                    infrared: None,
+                    colour: if LATE_DAY.contains(&ts) {
                         HSBK {
                             hue: 0,
                             saturation: 0,
                             brightness: 65535,
                             kelvin: 4000,
                         }
-                    } else if hour >= DAY_END && hour < NIGHT_END {
-                        let bright = rshift_calc(65535, 33000, hour, DAY_END, NIGHT_END, minute);
-                        let k = rshift_calc(4000, 3250, hour, DAY_END, NIGHT_END, minute);
+                    } else if let Some(left) = LATE_EVENING.remaining(&ts) {
+                        let bright = rshift_calc(65535, 33000, left);
+                        let k = rshift_calc(4000, 3250, left);
 
                         HSBK {
                             hue: 0,
                             saturation: 0,
-                            brightness: bright as u16,
-                            kelvin: k as u16,
+                            brightness: bright,
+                            kelvin: k,
                         }
                     } else {
                         HSBK {
@@ -184,58 +653,61 @@ impl LightPlan {
                 }) // End some
             }
             LightPlan::RedshiftToilet => {
-                let DAY_START = 8;
-                let DAY_END = 18;
-                let NIGHT_END = 23;
-
                 // This may need an extra stepping perhaps
 
                 Some(LightShift {
-                    duration: if hour >= DAY_END && hour < NIGHT_END {
+                    duration: if LATE_EVENING.contains(&ts) {
                         800
                     } else {
                         4000
                     },
-                    flicker: false,
-                    colour: if hour >= DAY_START && hour < DAY_END {
+                    effect: None,
+                    infrared: None,
+                    colour: if LATE_DAY.contains(&ts) {
                         HSBK {
                             hue: 0,
                             saturation: 0,
                             brightness: 65535,
                             kelvin: 3000,
                         }
-                    } else if hour >= DAY_END && hour < NIGHT_END {
-                        let bright = rshift_calc(65535, 7500, hour, DAY_END, NIGHT_END, minute);
-                        let k = rshift_calc(3000, 150, hour, DAY_END, NIGHT_END, minute);
+                    } else if let Some(left) = LATE_EVENING.remaining(&ts) {
+                        let bright = rshift_calc(65535, 7500, left);
+                        let k = rshift_calc(3000, 150, left);
 
                         HSBK {
                             hue: 0,
                             saturation: 0,
-                            brightness: bright as u16,
-                            kelvin: k as u16,
+                            brightness: bright,
+                            kelvin: k,
                         }
                     } else {
-                        HSBK {
-                            hue: 0,
-                            saturation: 0,
-                            brightness: 7500,
-                            kelvin: 150,
-                        }
+                        TOILET_NIGHT
                     },
                 }) // End some
             }
-            LightPlan::PartyHardMain => {
-                let mut rng = thread_rng();
-
-                Some(LightShift {
-                    duration: 2000,
-                    flicker: false,
-                    colour: party_colours.iter().choose(&mut rng).unwrap().clone(),
-                })
+            // Infrared is off by day, and on with the night light.
+            LightPlan::RedshiftDeck => {
+                LightPlan::RedshiftMain
+                    .shift_with(ts, rng)
+                    .map(|s| LightShift {
+                        infrared: Some(if DECK_DAY.contains(&ts) {
+                            0
+                        } else {
+                            INFRARED_NIGHT
+                        }),
+                        ..s
+                    })
             }
+            LightPlan::PartyHardMain => Some(LightShift {
+                duration: 2000,
+                effect: None,
+                infrared: None,
+                colour: *PARTY_COLOURS.iter().choose(rng).unwrap(),
+            }),
             LightPlan::PartyHardToilet => Some(LightShift {
                 duration: 65,
-                flicker: true,
+                effect: toilet_flicker(rng),
+                infrared: None,
                 colour: HSBK {
                     hue: 45074,
                     saturation: 65535,
@@ -245,13 +717,145 @@ impl LightPlan {
             }),
             LightPlan::Manual(hsbk) => Some(LightShift {
                 duration: 250,
-                flicker: false,
-                colour: hsbk.clone(),
+                effect: None,
+                infrared: None,
+                colour: *hsbk,
+            }),
+            LightPlan::Effect(kind, hsbk) => {
+                let off = HSBK {
+                    brightness: 0,
+                    ..*hsbk
+                };
+                let (colour, effect) = match kind {
+                    // Slowly swell down to a glow and back.
+                    LightEffectKind::Breathe => (
+                        *hsbk,
+                        LightEffect {
+                            colour: HSBK {
+                                brightness: hsbk.brightness / 10,
+                                ..*hsbk
+                            },
+                            waveform: LightWaveform::Sine,
+                            period: 4000,
+                            cycles: EFFECT_CYCLES,
+                            skew_ratio: 0,
+                            transient: true,
+                            brightness_only: true,
+                        },
+                    ),
+                    // Even on / off.
+                    LightEffectKind::Pulse => (
+                        *hsbk,
+                        LightEffect {
+                            colour: off,
+                            waveform: LightWaveform::Pulse,
+                            period: 1000,
+                            cycles: EFFECT_CYCLES,
+                            skew_ratio: 0,
+                            transient: true,
+                            brightness_only: false,
+                        },
+                    ),
+                    // Mostly dark with short flashes of the colour.
+                    LightEffectKind::Strobe => (
+                        off,
+                        LightEffect {
+                            colour: *hsbk,
+                            waveform: LightWaveform::Pulse,
+                            period: 100,
+                            cycles: EFFECT_CYCLES,
+                            skew_ratio: -24576,
+                            transient: true,
+                            brightness_only: false,
+                        },
+                    ),
+                };
+                Some(LightShift {
+                    duration: 250,
+                    effect: Some(effect),
+                    infrared: None,
+                    colour,
+                })
+            }
+            // Bulbs without zones get the middle of the gradient.
+            LightPlan::Gradient(from, to) => Some(LightShift {
+                duration: 250,
+                effect: None,
+                infrared: None,
+                colour: blend_hsbk(from, to, 0.5),
+            }),
+            LightPlan::Flame => Some(LightShift {
+                duration: 600,
+                effect: None,
+                infrared: None,
+                colour: blend_hsbk(&FLAME_TIP, &FLAME_BASE, rng.gen_range(0.6, 1.0)),
             }),
+            LightPlan::Circadian(b) => {
+                let level = circadian_level(&ts);
+                Some(LightShift {
+                    duration: (CIRCADIAN_STEP_SECS * 1000) as u32,
+                    effect: None,
+                    infrared: None,
+                    colour: HSBK {
+                        hue: 0,
+                        saturation: 0,
+                        brightness: blend(b.min_brightness, b.max_brightness, level),
+                        kelvin: blend(b.min_kelvin, b.max_kelvin, level),
+                    },
+                })
+            }
             LightPlan::Pause => None,
         }
     }
+
+    /// What the plan would do every step from from until to, without any bulbs.
+    /// A to at or before from is taken to be the next day. Steps where the plan
+    /// has nothing to say are left out.
+    pub fn preview(
+        &self,
+        from: DateTime<Tz>,
+        to: DateTime<Tz>,
+        step: Duration,
+    ) -> Vec<PreviewPoint> {
+        if step <= Duration::zero() {
+            return Vec::new();
+        }
+        let mut end = to;
+        if end <= from {
+            end = end + Duration::days(1);
+        }
+
+        let mut points = Vec::new();
+        let mut at = from;
+        while at < end && points.len() < PREVIEW_MAX_POINTS {
+            if let Some(shift) = self.shift(at) {
+                points.push(PreviewPoint { at, shift });
+            }
+            at = at + step;
+        }
+        points
+    }
+}
+
+/// One step of a plan preview.
+#[derive(Debug, PartialEq)]
+pub struct PreviewPoint {
+    pub at: DateTime<Tz>,
+    pub shift: LightShift,
 }
+
+// Previews are for looking at, so keep them to a size that can be.
+pub const PREVIEW_MAX_POINTS: usize = 2000;
+
+/// A time of day such as 18:00 or 18:30:15 to preview a plan from or to. Plans
+/// only look at the time, so this is on a fixed day in utc.
+pub fn preview_time(s: &str) -> Result<DateTime<Tz>, String> {
+    let t = NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|_| format!("Invalid time {}, expected HH:MM", s))?;
+    Ok(Tz::UTC.from_utc_datetime(&NaiveDate::from_ymd(2000, 1, 1).and_time(t)))
+}
+
 // struct party_main + expire time?
 
 // struct party_toilet + expire time?
@@ -0,0 +1,117 @@
+// Presence reports from sensors, which say whether anyone is in a room. They come
+// in over http, or from an mqtt broker when built with the mqtt feature.
+
+use serde_json::Value;
+
+/// Read an occupancy payload. Sensors differ, so this takes plain words such as
+/// on, off, true, false, 1 and 0, or json with an occupied or occupancy field
+/// as zigbee2mqtt sends.
+pub fn parse_payload(payload: &[u8]) -> Option<bool> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    match text.to_lowercase().as_str() {
+        "1" | "on" | "true" | "occupied" | "detected" => return Some(true),
+        "0" | "off" | "false" | "empty" | "vacant" | "clear" => return Some(false),
+        _ => {}
+    }
+    let v: Value = serde_json::from_str(text).ok()?;
+    v.get("occupied")
+        .or_else(|| v.get("occupancy"))
+        .and_then(Value::as_bool)
+}
+
+/// The room a topic is about, which is the part matching the + in filter, or the
+/// last part if there is no +.
+pub fn room_from_topic(filter: &str, topic: &str) -> Option<String> {
+    let filter: Vec<&str> = filter.split('/').collect();
+    let topic: Vec<&str> = topic.split('/').collect();
+    if filter.len() != topic.len() {
+        return None;
+    }
+    let mut room = None;
+    for (f, t) in filter.iter().zip(topic.iter()) {
+        match *f {
+            "+" if room.is_none() => room = Some(*t),
+            "+" => {}
+            f if f == *t => {}
+            _ => return None,
+        }
+    }
+    room.or_else(|| topic.last().cloned())
+        .filter(|r| !r.is_empty())
+        .map(str::to_string)
+}
+
+/// Where to find the broker, as host or host:port.
+pub fn broker_addr(broker: &str) -> Result<(String, u16), String> {
+    match broker.rfind(':') {
+        Some(i) if !broker[i..].contains(']') => broker[i + 1..]
+            .parse()
+            .map(|port| (broker[..i].to_string(), port))
+            .map_err(|_| format!("Invalid mqtt broker port in {}", broker)),
+        _ => Ok((broker.to_string(), 1883)),
+    }
+}
+
+/// The id to connect to the broker with. Brokers drop the older of two
+/// connections with the same id, so without one given each run gets its own.
+pub fn client_id(given: Option<&str>) -> String {
+    match given {
+        Some(id) => id.to_string(),
+        None => format!("lifx_ctl-{:08x}", rand::random::<u32>()),
+    }
+}
+
+/// Follow presence on an mqtt broker, passing each report to the manager. This
+/// runs on its own thread and reconnects whenever the broker goes away.
+#[cfg(feature = "mqtt")]
+pub fn start_mqtt(
+    broker: &str,
+    client_id: &str,
+    filter: &str,
+    lm: actix::Addr<crate::srv::LightManager>,
+) -> Result<std::thread::JoinHandle<()>, String> {
+    use crate::audit::Source;
+    use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+    let (host, port) = broker_addr(broker)?;
+    let mut opts = MqttOptions::new(client_id, host, port);
+    opts.set_keep_alive(std::time::Duration::from_secs(30));
+    let (mut client, mut connection) = Client::new(opts, 10);
+    let filter = filter.to_string();
+
+    std::thread::Builder::new()
+        .name("mqtt".to_string())
+        .spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    // Subscriptions don't outlive the session, so ask again each
+                    // time we connect.
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to mqtt, following {}", filter);
+                        if let Err(e) = client.try_subscribe(filter.as_str(), QoS::AtLeastOnce) {
+                            error!("Unable to subscribe to {} -> {:?}", filter, e);
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        let room = room_from_topic(&filter, &p.topic);
+                        match (room, parse_payload(&p.payload)) {
+                            (Some(room), Some(occupied)) => {
+                                lm.do_send(crate::srv::LightManagerPresence {
+                                    room,
+                                    occupied,
+                                    source: Source::Mqtt { topic: p.topic },
+                                })
+                            }
+                            _ => warn!("Ignoring presence on {} -> {:?}", p.topic, p.payload),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("Lost mqtt connection, retrying -> {}", e);
+                        std::thread::sleep(std::time::Duration::from_secs(5));
+                    }
+                }
+            }
+        })
+        .map_err(|e| format!("Unable to start mqtt thread -> {}", e))
+}
@@ -0,0 +1,147 @@
+// What each lifx product can do, from the product id in StateVersion. This only
+// covers lights, and uses the kelvin range of current firmware.
+
+use lifx_core::HSBK;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductInfo {
+    pub name: &'static str,
+    pub color: bool,
+    pub infrared: bool,
+    pub multizone: bool,
+    pub matrix: bool,
+    pub hev: bool,
+    pub min_kelvin: u16,
+    pub max_kelvin: u16,
+}
+
+impl ProductInfo {
+    /// Bring a colour into what this product can show. Says why if it had to
+    /// change anything.
+    pub fn fit(&self, colour: &HSBK) -> (HSBK, Option<String>) {
+        let mut fitted = *colour;
+        let mut why = Vec::new();
+
+        if !self.color && colour.saturation > 0 {
+            fitted.saturation = 0;
+            why.push(format!("{} can only do white", self.name));
+        }
+        if colour.kelvin < self.min_kelvin {
+            fitted.kelvin = self.min_kelvin;
+            why.push(format!(
+                "{}K is below the {} minimum of {}K",
+                colour.kelvin, self.name, self.min_kelvin
+            ));
+        } else if colour.kelvin > self.max_kelvin {
+            fitted.kelvin = self.max_kelvin;
+            why.push(format!(
+                "{}K is above the {} maximum of {}K",
+                colour.kelvin, self.name, self.max_kelvin
+            ));
+        }
+
+        if why.is_empty() {
+            (fitted, None)
+        } else {
+            (fitted, Some(why.join(", ")))
+        }
+    }
+}
+
+const LIFX_VENDOR: u32 = 1;
+
+macro_rules! product {
+    ($name:expr, $color:expr, $ir:expr, $mz:expr, $matrix:expr, $hev:expr, $kmin:expr, $kmax:expr) => {
+        ProductInfo {
+            name: $name,
+            color: $color,
+            infrared: $ir,
+            multizone: $mz,
+            matrix: $matrix,
+            hev: $hev,
+            min_kelvin: $kmin,
+            max_kelvin: $kmax,
+        }
+    };
+}
+
+/// What we assume of a device we don't know, which is what most lifx bulbs can do.
+pub static UNKNOWN: ProductInfo = product!("Unknown", true, false, false, false, false, 2500, 9000);
+
+// Columns are: color, infrared, multizone, matrix, hev, min kelvin, max kelvin.
+#[rustfmt::skip]
+static PRODUCTS: &[(u32, ProductInfo)] = &[
+    (1, product!("LIFX Original 1000", true, false, false, false, false, 2500, 9000)),
+    (3, product!("LIFX Color 650", true, false, false, false, false, 2500, 9000)),
+    (10, product!("LIFX White 800 (Low Voltage)", false, false, false, false, false, 2700, 6500)),
+    (11, product!("LIFX White 800 (High Voltage)", false, false, false, false, false, 2700, 6500)),
+    (15, product!("LIFX Color 1000", true, false, false, false, false, 2500, 9000)),
+    (18, product!("LIFX White 900 BR30 (Low Voltage)", false, false, false, false, false, 2500, 9000)),
+    (19, product!("LIFX White 900 BR30 (High Voltage)", false, false, false, false, false, 2500, 9000)),
+    (20, product!("LIFX Color 1000 BR30", true, false, false, false, false, 2500, 9000)),
+    (22, product!("LIFX Color 1000", true, false, false, false, false, 2500, 9000)),
+    (27, product!("LIFX A19", true, false, false, false, false, 2500, 9000)),
+    (28, product!("LIFX BR30", true, false, false, false, false, 2500, 9000)),
+    (29, product!("LIFX A19 Night Vision", true, true, false, false, false, 2500, 9000)),
+    (30, product!("LIFX BR30 Night Vision", true, true, false, false, false, 2500, 9000)),
+    (31, product!("LIFX Z", true, false, true, false, false, 2500, 9000)),
+    (32, product!("LIFX Z", true, false, true, false, false, 2500, 9000)),
+    (36, product!("LIFX Downlight", true, false, false, false, false, 2500, 9000)),
+    (37, product!("LIFX Downlight", true, false, false, false, false, 2500, 9000)),
+    (38, product!("LIFX Beam", true, false, true, false, false, 2500, 9000)),
+    (39, product!("LIFX Downlight White to Warm", false, false, false, false, false, 1500, 9000)),
+    (40, product!("LIFX Downlight", true, false, false, false, false, 2500, 9000)),
+    (43, product!("LIFX A19", true, false, false, false, false, 2500, 9000)),
+    (44, product!("LIFX BR30", true, false, false, false, false, 2500, 9000)),
+    (45, product!("LIFX A19 Night Vision", true, true, false, false, false, 2500, 9000)),
+    (46, product!("LIFX BR30 Night Vision", true, true, false, false, false, 2500, 9000)),
+    (49, product!("LIFX Mini Color", true, false, false, false, false, 1500, 9000)),
+    (50, product!("LIFX Mini White to Warm", false, false, false, false, false, 1500, 4000)),
+    (51, product!("LIFX Mini White", false, false, false, false, false, 2700, 2700)),
+    (52, product!("LIFX GU10", true, false, false, false, false, 1500, 9000)),
+    (53, product!("LIFX GU10", true, false, false, false, false, 1500, 9000)),
+    (55, product!("LIFX Tile", true, false, false, true, false, 2500, 9000)),
+    (57, product!("LIFX Candle", true, false, false, true, false, 1500, 9000)),
+    (59, product!("LIFX Mini Color", true, false, false, false, false, 1500, 9000)),
+    (60, product!("LIFX Mini White to Warm", false, false, false, false, false, 1500, 4000)),
+    (61, product!("LIFX Mini White", false, false, false, false, false, 2700, 2700)),
+    (62, product!("LIFX A19", true, false, false, false, false, 1500, 9000)),
+    (63, product!("LIFX BR30", true, false, false, false, false, 1500, 9000)),
+    (64, product!("LIFX A19 Night Vision", true, true, false, false, false, 1500, 9000)),
+    (65, product!("LIFX BR30 Night Vision", true, true, false, false, false, 1500, 9000)),
+    (66, product!("LIFX Mini White", false, false, false, false, false, 2700, 2700)),
+    (68, product!("LIFX Candle", true, false, false, true, false, 1500, 9000)),
+    (81, product!("LIFX Candle White to Warm", false, false, false, false, false, 2200, 6500)),
+    (82, product!("LIFX Filament Clear", false, false, false, false, false, 2100, 2100)),
+    (85, product!("LIFX Filament Amber", false, false, false, false, false, 2000, 2000)),
+    (87, product!("LIFX Mini White", false, false, false, false, false, 2700, 2700)),
+    (88, product!("LIFX Mini White", false, false, false, false, false, 2700, 2700)),
+    (90, product!("LIFX Clean", true, false, false, false, true, 1500, 9000)),
+    (91, product!("LIFX Color", true, false, false, false, false, 1500, 9000)),
+    (92, product!("LIFX Color", true, false, false, false, false, 1500, 9000)),
+    (94, product!("LIFX BR30", true, false, false, false, false, 1500, 9000)),
+    (96, product!("LIFX Candle White to Warm", false, false, false, false, false, 2200, 6500)),
+    (97, product!("LIFX A19", true, false, false, false, false, 1500, 9000)),
+    (98, product!("LIFX BR30", true, false, false, false, false, 1500, 9000)),
+    (99, product!("LIFX Clean", true, false, false, false, true, 1500, 9000)),
+    (100, product!("LIFX Filament Clear", false, false, false, false, false, 2100, 2100)),
+    (101, product!("LIFX Filament Amber", false, false, false, false, false, 2000, 2000)),
+    (109, product!("LIFX A19 Night Vision", true, true, false, false, false, 1500, 9000)),
+    (110, product!("LIFX BR30 Night Vision", true, true, false, false, false, 1500, 9000)),
+    (111, product!("LIFX A19 Night Vision", true, true, false, false, false, 1500, 9000)),
+    (112, product!("LIFX BR30 Night Vision", true, true, false, false, false, 1500, 9000)),
+    (117, product!("LIFX Z", true, false, true, false, false, 1500, 9000)),
+    (118, product!("LIFX Z", true, false, true, false, false, 1500, 9000)),
+    (119, product!("LIFX Beam", true, false, true, false, false, 1500, 9000)),
+    (120, product!("LIFX Beam", true, false, true, false, false, 1500, 9000)),
+];
+
+pub fn lookup(vendor: u32, product: u32) -> Option<&'static ProductInfo> {
+    if vendor != LIFX_VENDOR {
+        return None;
+    }
+    PRODUCTS
+        .iter()
+        .find(|(id, _)| *id == product)
+        .map(|(_, info)| info)
+}
@@ -0,0 +1,346 @@
+use lifx_core::{RawMessage, HSBK};
+
+// Newer lifx messages that lifx_core doesn't know about. These are encoded by
+// hand and spliced into a RawMessage, so the header is still built by lifx_core.
+
+// Size of the frame, frame address and protocol header.
+const HEADER_SIZE: usize = 36;
+
+/// How many zones fit in one SetExtendedColorZones.
+pub const EXTENDED_ZONES: usize = 82;
+
+/// How many pixels fit in one Set64.
+pub const TILE_PIXELS: usize = 64;
+
+// Each tile in StateDeviceChain, and how many there is room for.
+const TILE_INFO_SIZE: usize = 55;
+const CHAIN_TILES: usize = 16;
+
+/// One tile in a device chain. Tiles and Candles are both chains, the Candle
+/// just only ever has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileInfo {
+    pub width: u8,
+    pub height: u8,
+}
+
+/// How the last HEV clean cycle ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HevResult {
+    Success,
+    Busy,
+    InterruptedByReset,
+    InterruptedByHomekit,
+    InterruptedByLan,
+    InterruptedByCloud,
+    None,
+}
+
+impl HevResult {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => HevResult::Success,
+            1 => HevResult::Busy,
+            2 => HevResult::InterruptedByReset,
+            3 => HevResult::InterruptedByHomekit,
+            4 => HevResult::InterruptedByLan,
+            5 => HevResult::InterruptedByCloud,
+            _ => HevResult::None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            HevResult::Success => 0,
+            HevResult::Busy => 1,
+            HevResult::InterruptedByReset => 2,
+            HevResult::InterruptedByHomekit => 3,
+            HevResult::InterruptedByLan => 4,
+            HevResult::InterruptedByCloud => 5,
+            HevResult::None => 255,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtMessage {
+    // 3. lifx_core knows this one, but writes the port before the service so
+    // what it builds doesn't read back. Only built here, never decoded.
+    StateService {
+        service: u8,
+        port: u32,
+    },
+    // 142
+    GetHevCycle,
+    // 143. A duration of 0 uses the device's default.
+    SetHevCycle {
+        enable: bool,
+        duration_s: u32,
+    },
+    // 144. Remaining is 0 when no cycle is running.
+    StateHevCycle {
+        duration_s: u32,
+        remaining_s: u32,
+        last_power: bool,
+    },
+    // 148
+    GetLastHevCycleResult,
+    // 149
+    StateLastHevCycleResult {
+        result: HevResult,
+    },
+    // 510. Sets up to 82 zones from index in one go.
+    SetExtendedColorZones {
+        duration: u32,
+        apply: u8,
+        index: u16,
+        colors: Vec<HSBK>,
+    },
+    // 701
+    GetDeviceChain,
+    // 702. Only the tiles the device has, not the empty slots.
+    StateDeviceChain {
+        start_index: u8,
+        tiles: Vec<TileInfo>,
+    },
+    // 715. Sets a rectangle of pixels on one tile, in rows from x, y.
+    Set64 {
+        tile_index: u8,
+        length: u8,
+        x: u8,
+        y: u8,
+        width: u8,
+        duration: u32,
+        colors: Vec<HSBK>,
+    },
+}
+
+impl ExtMessage {
+    fn typ(&self) -> u16 {
+        match self {
+            ExtMessage::StateService { .. } => 3,
+            ExtMessage::GetHevCycle => 142,
+            ExtMessage::SetHevCycle { .. } => 143,
+            ExtMessage::StateHevCycle { .. } => 144,
+            ExtMessage::GetLastHevCycleResult => 148,
+            ExtMessage::StateLastHevCycleResult { .. } => 149,
+            ExtMessage::SetExtendedColorZones { .. } => 510,
+            ExtMessage::GetDeviceChain => 701,
+            ExtMessage::StateDeviceChain { .. } => 702,
+            ExtMessage::Set64 { .. } => 715,
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut p = Vec::new();
+        match self {
+            ExtMessage::GetHevCycle
+            | ExtMessage::GetLastHevCycleResult
+            | ExtMessage::GetDeviceChain => {}
+            ExtMessage::StateService { service, port } => {
+                p.push(*service);
+                p.extend_from_slice(&port.to_le_bytes());
+            }
+            ExtMessage::SetHevCycle { enable, duration_s } => {
+                p.push(*enable as u8);
+                p.extend_from_slice(&duration_s.to_le_bytes());
+            }
+            ExtMessage::StateHevCycle {
+                duration_s,
+                remaining_s,
+                last_power,
+            } => {
+                p.extend_from_slice(&duration_s.to_le_bytes());
+                p.extend_from_slice(&remaining_s.to_le_bytes());
+                p.push(*last_power as u8);
+            }
+            ExtMessage::StateLastHevCycleResult { result } => p.push(result.to_u8()),
+            ExtMessage::SetExtendedColorZones {
+                duration,
+                apply,
+                index,
+                colors,
+            } => {
+                p.extend_from_slice(&duration.to_le_bytes());
+                p.push(*apply);
+                p.extend_from_slice(&index.to_le_bytes());
+                p.push(colors.len().min(EXTENDED_ZONES) as u8);
+                put_colours(&mut p, colors, EXTENDED_ZONES);
+            }
+            ExtMessage::StateDeviceChain { start_index, tiles } => {
+                p.push(*start_index);
+                for i in 0..CHAIN_TILES {
+                    let mut t = [0u8; TILE_INFO_SIZE];
+                    if let Some(tile) = tiles.get(i) {
+                        t[16] = tile.width;
+                        t[17] = tile.height;
+                    }
+                    p.extend_from_slice(&t);
+                }
+                p.push(tiles.len().min(CHAIN_TILES) as u8);
+            }
+            ExtMessage::Set64 {
+                tile_index,
+                length,
+                x,
+                y,
+                width,
+                duration,
+                colors,
+            } => {
+                p.extend_from_slice(&[*tile_index, *length, 0, *x, *y, *width]);
+                p.extend_from_slice(&duration.to_le_bytes());
+                put_colours(&mut p, colors, TILE_PIXELS);
+            }
+        }
+        p
+    }
+
+    /// Decode the messages we know that lifx_core doesn't. The sets are only
+    /// ever sent to devices, but the simulator needs to read them.
+    pub fn from_raw(raw: &RawMessage) -> Option<Self> {
+        let p = &raw.payload;
+        match raw.protocol_header.typ {
+            142 => Some(ExtMessage::GetHevCycle),
+            143 if p.len() >= 5 => Some(ExtMessage::SetHevCycle {
+                enable: p[0] != 0,
+                duration_s: get_u32(&p[1..5]),
+            }),
+            144 if p.len() >= 9 => Some(ExtMessage::StateHevCycle {
+                duration_s: get_u32(&p[0..4]),
+                remaining_s: get_u32(&p[4..8]),
+                last_power: p[8] != 0,
+            }),
+            148 => Some(ExtMessage::GetLastHevCycleResult),
+            149 if !p.is_empty() => Some(ExtMessage::StateLastHevCycleResult {
+                result: HevResult::from_u8(p[0]),
+            }),
+            510 if p.len() >= 8 + EXTENDED_ZONES * 8 => {
+                let count = (p[7] as usize).min(EXTENDED_ZONES);
+                Some(ExtMessage::SetExtendedColorZones {
+                    duration: get_u32(&p[0..4]),
+                    apply: p[4],
+                    index: get_u16(&p[5..7]),
+                    colors: get_colours(&p[8..], count),
+                })
+            }
+            701 => Some(ExtMessage::GetDeviceChain),
+            702 => {
+                if p.len() < 2 + CHAIN_TILES * TILE_INFO_SIZE {
+                    return None;
+                }
+                let count = (p[1 + CHAIN_TILES * TILE_INFO_SIZE] as usize).min(CHAIN_TILES);
+                let tiles = (0..count)
+                    .map(|i| {
+                        let t = &p[1 + i * TILE_INFO_SIZE..];
+                        TileInfo {
+                            width: t[16],
+                            height: t[17],
+                        }
+                    })
+                    .collect();
+                Some(ExtMessage::StateDeviceChain {
+                    start_index: p[0],
+                    tiles,
+                })
+            }
+            715 if p.len() >= 10 + TILE_PIXELS * 8 => Some(ExtMessage::Set64 {
+                tile_index: p[0],
+                length: p[1],
+                x: p[3],
+                y: p[4],
+                width: p[5],
+                duration: get_u32(&p[6..10]),
+                colors: get_colours(&p[10..], TILE_PIXELS),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Replace the message in raw with this one, keeping the header.
+    pub fn patch(&self, raw: &mut RawMessage) {
+        raw.protocol_header.typ = self.typ();
+        raw.payload = self.payload();
+        raw.frame.size = (HEADER_SIZE + raw.payload.len()) as u16;
+    }
+}
+
+fn get_u16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&b[..4]);
+    u32::from_le_bytes(v)
+}
+
+fn get_colours(b: &[u8], count: usize) -> Vec<HSBK> {
+    b.chunks(8)
+        .take(count)
+        .map(|c| HSBK {
+            hue: get_u16(&c[0..2]),
+            saturation: get_u16(&c[2..4]),
+            brightness: get_u16(&c[4..6]),
+            kelvin: get_u16(&c[6..8]),
+        })
+        .collect()
+}
+
+fn put_hsbk(p: &mut Vec<u8>, c: &HSBK) {
+    p.extend_from_slice(&c.hue.to_le_bytes());
+    p.extend_from_slice(&c.saturation.to_le_bytes());
+    p.extend_from_slice(&c.brightness.to_le_bytes());
+    p.extend_from_slice(&c.kelvin.to_le_bytes());
+}
+
+// Colour arrays are always full size, and unused slots are ignored.
+fn put_colours(p: &mut Vec<u8>, colors: &[HSBK], size: usize) {
+    let blank = HSBK {
+        hue: 0,
+        saturation: 0,
+        brightness: 0,
+        kelvin: 0,
+    };
+    colors
+        .iter()
+        .chain(std::iter::repeat(&blank))
+        .take(size)
+        .for_each(|c| put_hsbk(p, c));
+}
+
+/// Anything we can send to or get from a device.
+#[derive(Debug, Clone)]
+pub enum LifxMessage {
+    Core(lifx_core::Message),
+    Ext(ExtMessage),
+}
+
+impl LifxMessage {
+    pub fn from_raw(raw: &RawMessage) -> Result<Self, lifx_core::Error> {
+        match ExtMessage::from_raw(raw) {
+            Some(m) => Ok(LifxMessage::Ext(m)),
+            None => lifx_core::Message::from_raw(raw).map(LifxMessage::Core),
+        }
+    }
+
+    /// The packet type number from the lifx header, for logging.
+    pub fn typ(&self) -> u16 {
+        match self {
+            LifxMessage::Core(m) => m.get_num(),
+            LifxMessage::Ext(m) => m.typ(),
+        }
+    }
+}
+
+impl From<lifx_core::Message> for LifxMessage {
+    fn from(m: lifx_core::Message) -> Self {
+        LifxMessage::Core(m)
+    }
+}
+
+impl From<ExtMessage> for LifxMessage {
+    fn from(m: ExtMessage) -> Self {
+        LifxMessage::Ext(m)
+    }
+}
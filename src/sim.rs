@@ -0,0 +1,523 @@
+use crate::products;
+use crate::proto::{ExtMessage, HevResult, LifxMessage, TileInfo};
+use lifx_core::{BuildOptions, LifxString, Message, PowerLevel, RawMessage, Service, HSBK};
+use rand::Rng;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A pretend bulb that answers the lan protocol like a real one, so the whole
+// stack can be run against it on one machine.
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub label: String,
+    pub target: [u8; 6],
+    // Reported in StateVersion, which decides the capabilities the server uses.
+    pub product: u32,
+    // Firmware as major, minor.
+    pub firmware: (u16, u16),
+    // Chance of dropping each packet that arrives, from 0 to 1.
+    pub loss: f32,
+    // How long to wait before answering.
+    pub latency: Duration,
+    // How many zones a strip has, or 0 for anything else.
+    pub zones: usize,
+    // The size of each tile, for Tiles and Candles.
+    pub tiles: Vec<TileInfo>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            label: "sim".to_string(),
+            target: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01],
+            // LIFX A19
+            product: 27,
+            firmware: (2, 80),
+            loss: 0.0,
+            latency: Duration::from_millis(0),
+            zones: 0,
+            tiles: Vec::new(),
+        }
+    }
+}
+
+/// What the pretend bulb is showing.
+#[derive(Debug, Clone)]
+pub struct SimState {
+    pub colour: HSBK,
+    pub power: PowerLevel,
+    pub label: String,
+    pub infrared: u16,
+    pub zones: Vec<HSBK>,
+    // Each tile's pixels, in rows.
+    pub tiles: Vec<Vec<HSBK>>,
+    // How long the last clean cycle was to run for, and when the running one ends.
+    pub hev_duration: u32,
+    pub hev_until: Option<Instant>,
+    pub hev_result: HevResult,
+    // Every packet that wasn't dropped, for tests to check.
+    pub received: usize,
+    pub dropped: usize,
+}
+
+/// A running simulated bulb. It stops when this is dropped.
+pub struct SimBulb {
+    addr: SocketAddr,
+    state: Arc<Mutex<SimState>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SimBulb {
+    /// Listen on bind, which may have port 0 to pick any free port.
+    pub fn spawn(bind: SocketAddr, config: SimConfig) -> std::io::Result<Self> {
+        let sock = UdpSocket::bind(bind)?;
+        // Wake up often enough to notice being stopped.
+        sock.set_read_timeout(Some(Duration::from_millis(50)))?;
+        let addr = sock.local_addr()?;
+
+        let colour = HSBK {
+            hue: 0,
+            saturation: 0,
+            brightness: 65535,
+            kelvin: 3500,
+        };
+        let state = Arc::new(Mutex::new(SimState {
+            colour,
+            power: PowerLevel::Enabled,
+            label: config.label.clone(),
+            infrared: 0,
+            zones: vec![colour; config.zones],
+            tiles: config
+                .tiles
+                .iter()
+                .map(|t| vec![colour; t.width as usize * t.height as usize])
+                .collect(),
+            hev_duration: 0,
+            hev_until: None,
+            hev_result: HevResult::None,
+            received: 0,
+            dropped: 0,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let sim = Sim {
+            sock,
+            config,
+            state: state.clone(),
+        };
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || sim.run(&thread_stop));
+
+        Ok(SimBulb {
+            addr,
+            state,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn state(&self) -> SimState {
+        self.state.lock().expect("sim state poisoned").clone()
+    }
+
+    /// Block until the bulb is stopped from elsewhere.
+    pub fn join(mut self) {
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for SimBulb {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+struct Sim {
+    sock: UdpSocket,
+    config: SimConfig,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Sim {
+    fn run(&self, stop: &AtomicBool) {
+        let mut buf = [0u8; 1024];
+        while !stop.load(Ordering::Relaxed) {
+            let (len, from) = match self.sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                // Timed out, or the socket is gone.
+                Err(_) => continue,
+            };
+            if let Err(e) = self.handle(&buf[..len], from) {
+                debug!(
+                    "sim {} ignoring packet from {} -> {}",
+                    self.config.label, from, e
+                );
+            }
+        }
+    }
+
+    fn handle(&self, bytes: &[u8], from: SocketAddr) -> Result<(), String> {
+        let raw = RawMessage::unpack(bytes).map_err(|e| format!("{:?}", e))?;
+        let ours = crate::srv::target_to_u64(&self.config.target);
+        if raw.frame_addr.target != 0 && raw.frame_addr.target != ours {
+            return Ok(());
+        }
+
+        if rand::thread_rng().gen::<f32>() < self.config.loss {
+            self.state.lock().map_err(|_| "poisoned")?.dropped += 1;
+            return Ok(());
+        }
+
+        let msg = LifxMessage::from_raw(&raw).map_err(|e| format!("{:?}", e))?;
+        let mut replies: Vec<LifxMessage> = Vec::new();
+        if raw.frame_addr.ack_required {
+            replies.push(
+                Message::Acknowledgement {
+                    seq: raw.frame_addr.sequence,
+                }
+                .into(),
+            );
+        }
+        replies.extend(self.apply(msg, raw.frame_addr.res_required));
+
+        let opts = BuildOptions {
+            target: Some(ours),
+            source: raw.frame.source,
+            sequence: raw.frame_addr.sequence,
+            ..Default::default()
+        };
+        let packets = replies
+            .into_iter()
+            .map(|r| build(&opts, r))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{:?}", e))?;
+
+        // Each packet's replies wait on their own, so a slow answer doesn't hold up
+        // the ones behind it.
+        let latency = self.config.latency;
+        if latency > Duration::from_millis(0) {
+            let sock = self.sock.try_clone().map_err(|e| format!("{:?}", e))?;
+            thread::spawn(move || {
+                thread::sleep(latency);
+                for out in packets {
+                    let _ = sock.send_to(&out, from);
+                }
+            });
+        } else {
+            for out in packets {
+                self.sock
+                    .send_to(&out, from)
+                    .map_err(|e| format!("{:?}", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Update the state for msg, and give back what a real bulb would reply with.
+    fn apply(&self, msg: LifxMessage, res_required: bool) -> Vec<LifxMessage> {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return Vec::new(),
+        };
+        state.received += 1;
+
+        match msg {
+            LifxMessage::Core(Message::GetService) => vec![ExtMessage::StateService {
+                service: Service::UDP as u8,
+                port: self.sock.local_addr().map(|a| a.port() as u32).unwrap_or(0),
+            }
+            .into()],
+            LifxMessage::Core(Message::GetColorZones {
+                start_index,
+                end_index,
+            }) => zone_states(&state, start_index, end_index)
+                .into_iter()
+                .map(LifxMessage::Core)
+                .collect(),
+            LifxMessage::Core(m) => self
+                .apply_core(&mut state, m, res_required)
+                .into_iter()
+                .map(LifxMessage::Core)
+                .collect(),
+            LifxMessage::Ext(m) => self
+                .apply_ext(&mut state, m, res_required)
+                .into_iter()
+                .map(LifxMessage::Ext)
+                .collect(),
+        }
+    }
+
+    fn apply_core(
+        &self,
+        state: &mut SimState,
+        msg: Message,
+        res_required: bool,
+    ) -> Option<Message> {
+        let light_state = |s: &SimState| Message::LightState {
+            color: s.colour,
+            reserved: 0,
+            power: s.power,
+            label: LifxString::new(&s.label),
+            reserved2: 0,
+        };
+
+        match msg {
+            Message::GetLabel => Some(Message::StateLabel {
+                label: LifxString::new(&state.label),
+            }),
+            Message::SetLabel { label } => {
+                state.label = label.to_string();
+                Some(Message::StateLabel { label })
+            }
+            Message::GetVersion => Some(Message::StateVersion {
+                vendor: 1,
+                product: self.config.product,
+                version: 0,
+            }),
+            Message::GetHostFirmware => Some(Message::StateHostFirmware {
+                build: 0,
+                reserved: 0,
+                version: ((self.config.firmware.0 as u32) << 16) | self.config.firmware.1 as u32,
+            }),
+            Message::GetPower => Some(Message::StatePower { level: state.power }),
+            Message::SetPower { level } => {
+                state.power = level;
+                if res_required {
+                    Some(Message::StatePower { level })
+                } else {
+                    None
+                }
+            }
+            Message::LightGetPower => Some(Message::LightStatePower {
+                level: state.power as u16,
+            }),
+            Message::LightSetPower { level, .. } => {
+                // The light messages take any level, but a bulb is only on or off.
+                state.power = if level == 0 {
+                    PowerLevel::Standby
+                } else {
+                    PowerLevel::Enabled
+                };
+                if res_required {
+                    Some(Message::LightStatePower { level })
+                } else {
+                    None
+                }
+            }
+            Message::LightGet => Some(light_state(state)),
+            Message::LightSetColor { color, .. } => {
+                // Every zone and pixel goes to the one colour.
+                state.colour = color;
+                state.zones.iter_mut().for_each(|z| *z = color);
+                state
+                    .tiles
+                    .iter_mut()
+                    .flat_map(|t| t.iter_mut())
+                    .for_each(|p| *p = color);
+                if res_required {
+                    Some(light_state(state))
+                } else {
+                    None
+                }
+            }
+            // Older strips set a range at a time, applied straight away here.
+            Message::SetColorZones {
+                start_index,
+                end_index,
+                color,
+                ..
+            } => {
+                state
+                    .zones
+                    .iter_mut()
+                    .take(end_index as usize + 1)
+                    .skip(start_index as usize)
+                    .for_each(|z| *z = color);
+                None
+            }
+            Message::LightGetInfrared => Some(Message::LightStateInfrared {
+                brightness: state.infrared,
+            }),
+            Message::LightSetInfrared { brightness } => {
+                state.infrared = brightness;
+                if res_required {
+                    Some(Message::LightStateInfrared { brightness })
+                } else {
+                    None
+                }
+            }
+            // Waveforms run on the bulb and leave the colour where it was.
+            _ => None,
+        }
+    }
+
+    fn apply_ext(
+        &self,
+        state: &mut SimState,
+        msg: ExtMessage,
+        res_required: bool,
+    ) -> Option<ExtMessage> {
+        let can_clean = products::lookup(1, self.config.product)
+            .map(|p| p.hev)
+            .unwrap_or(false);
+        match msg {
+            ExtMessage::GetHevCycle if can_clean => Some(hev_state(state)),
+            ExtMessage::SetHevCycle { enable, duration_s } if can_clean => {
+                let now = Instant::now();
+                let running = state.hev_until.map(|u| u > now).unwrap_or(false);
+                if enable {
+                    // 0 is the bulb's own default of two hours.
+                    let duration_s = if duration_s == 0 { 7200 } else { duration_s };
+                    state.hev_duration = duration_s;
+                    state.hev_until = Some(now + Duration::from_secs(duration_s as u64));
+                } else if running {
+                    state.hev_until = None;
+                    state.hev_result = HevResult::InterruptedByLan;
+                }
+                if res_required {
+                    Some(hev_state(state))
+                } else {
+                    None
+                }
+            }
+            ExtMessage::GetLastHevCycleResult if can_clean => {
+                hev_state(state);
+                Some(ExtMessage::StateLastHevCycleResult {
+                    result: state.hev_result,
+                })
+            }
+            ExtMessage::SetExtendedColorZones { index, colors, .. } => {
+                // Applied straight away, whatever apply asks for.
+                state
+                    .zones
+                    .iter_mut()
+                    .skip(index as usize)
+                    .zip(colors)
+                    .for_each(|(z, c)| *z = c);
+                None
+            }
+            ExtMessage::GetDeviceChain if !self.config.tiles.is_empty() => {
+                Some(ExtMessage::StateDeviceChain {
+                    start_index: 0,
+                    tiles: self.config.tiles.clone(),
+                })
+            }
+            ExtMessage::Set64 {
+                tile_index,
+                length,
+                x,
+                y,
+                width,
+                colors,
+                ..
+            } => {
+                let width = width.max(1) as usize;
+                let first = tile_index as usize;
+                for (info, pixels) in self
+                    .config
+                    .tiles
+                    .iter()
+                    .zip(state.tiles.iter_mut())
+                    .skip(first)
+                    .take(length.max(1) as usize)
+                {
+                    for (i, c) in colors.iter().enumerate() {
+                        let px = x as usize + i % width;
+                        let py = y as usize + i / width;
+                        if px < info.width as usize && py < info.height as usize {
+                            pixels[py * info.width as usize + px] = *c;
+                        }
+                    }
+                }
+                None
+            }
+            // Anything this device can't do, which a real one would answer with
+            // StateUnhandled.
+            _ => None,
+        }
+    }
+}
+
+// Build a reply, whether lifx_core knows it or not.
+fn build(opts: &BuildOptions, msg: LifxMessage) -> Result<Vec<u8>, lifx_core::Error> {
+    match msg {
+        LifxMessage::Core(m) => RawMessage::build(opts, m)?.pack(),
+        LifxMessage::Ext(m) => {
+            let mut raw = RawMessage::build(opts, Message::GetService)?;
+            m.patch(&mut raw);
+            raw.pack()
+        }
+    }
+}
+
+// The zones from start to end, eight to a StateMultiZone like a real strip.
+fn zone_states(state: &SimState, start: u8, end: u8) -> Vec<Message> {
+    let count = state.zones.len();
+    if count == 0 {
+        return Vec::new();
+    }
+    let end = (end as usize).min(count - 1);
+    let start = start as usize;
+    if start > end {
+        return Vec::new();
+    }
+    if start == end {
+        return vec![Message::StateZone {
+            count: count as u8,
+            index: start as u8,
+            color: state.zones[start],
+        }];
+    }
+    (start..=end)
+        .step_by(8)
+        .map(|index| {
+            // Past the end of the strip is padded with the last zone.
+            let zone = |i: usize| state.zones[(index + i).min(count - 1)];
+            Message::StateMultiZone {
+                count: count as u8,
+                index: index as u8,
+                color0: zone(0),
+                color1: zone(1),
+                color2: zone(2),
+                color3: zone(3),
+                color4: zone(4),
+                color5: zone(5),
+                color6: zone(6),
+                color7: zone(7),
+            }
+        })
+        .collect()
+}
+
+// The running clean cycle, noting when one has finished since we last looked.
+fn hev_state(state: &mut SimState) -> ExtMessage {
+    let now = Instant::now();
+    let remaining = match state.hev_until {
+        Some(until) if until > now => (until - now).as_secs() as u32,
+        Some(_) => {
+            state.hev_until = None;
+            state.hev_result = HevResult::Success;
+            0
+        }
+        None => 0,
+    };
+    ExtMessage::StateHevCycle {
+        duration_s: state.hev_duration,
+        remaining_s: remaining,
+        last_power: false,
+    }
+}
@@ -1,78 +1,505 @@
+use crate::audit::{AuditActor, AuditEntry, AuditRecord, BulbChange, BulbState, Source};
+use crate::clock::{self, Clock, SharedRng, SystemClock};
 use crate::plans;
+use crate::products::{self, ProductInfo};
+use crate::proto::{ExtMessage, HevResult, LifxMessage, EXTENDED_ZONES, TILE_PIXELS};
+use crate::webhook::{StateEvent, WebhookActor, WebhookNotify};
 use actix::prelude::*;
+use chrono::{DateTime, Duration as ChronoDuration, Timelike};
+use chrono_tz::Tz;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use lifx_core::HSBK;
 
-use rand::{thread_rng, Rng};
+// Every packet goes out once. Sets ask the bulb to acknowledge them, and are
+// sent again if it doesn't.
+macro_rules! send_bytes {
+    ($sock:expr, $bytes:expr, $addr:expr) => {{
+        if let Err(e) = $sock.send_to($bytes, $addr) {
+            warn!(addr = %$addr, "Failed to send -> {}", e);
+        }
+    }};
+}
+
+// The actual lifx control bits
+
+// Our source id in every header. Replies are addressed back to this, which is
+// how we tell our traffic apart from other lifx apps on the network.
+const LIFX_SOURCE: u32 = 0x6c69_6678;
+
+/// Turn a bulb's 6 byte mac into the target field of the lifx header.
+pub fn target_to_u64(target: &[u8; 6]) -> u64 {
+    let mut b = [0u8; 8];
+    b[..6].copy_from_slice(target);
+    u64::from_le_bytes(b)
+}
+
+/// The 6 byte mac from the target field of the lifx header.
+pub fn u64_to_target(target: u64) -> [u8; 6] {
+    let b = target.to_le_bytes();
+    let mut t = [0u8; 6];
+    t.copy_from_slice(&b[..6]);
+    t
+}
+
+/// Builds and decodes lifx packets for one source id, keeping track of the
+/// sequence number. Shared by the controller and direct mode.
+pub struct LifxPackets {
+    source: u32,
+    sequence: u8,
+}
+
+impl LifxPackets {
+    pub fn new(source: u32) -> Self {
+        LifxPackets {
+            source,
+            sequence: 0,
+        }
+    }
+
+    pub fn source(&self) -> u32 {
+        self.source
+    }
+
+    /// The sequence number of the last packet built.
+    pub fn sequence(&self) -> u8 {
+        self.sequence
+    }
 
-// Helper for internal logging.
-macro_rules! log_event {
-    ($log_addr:expr, $($arg:tt)*) => ({
-        $log_addr.do_send(
-            LogEvent {
-                msg: std::fmt::format(
-                    format_args!($($arg)*)
-                )
+    /// Build the bytes for msg. With no target the packet is tagged, and every
+    /// device that receives it will act on it.
+    pub fn pack<M: Into<LifxMessage>>(
+        &mut self,
+        target: Option<u64>,
+        ack_required: bool,
+        msg: M,
+    ) -> Result<Vec<u8>, lifx_core::Error> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let opts = lifx_core::BuildOptions {
+            target,
+            ack_required,
+            source: self.source,
+            sequence: self.sequence,
+            ..Default::default()
+        };
+        match msg.into() {
+            LifxMessage::Core(m) => lifx_core::RawMessage::build(&opts, m)?.pack(),
+            LifxMessage::Ext(m) => {
+                // Build any message to get the header, then swap ours in.
+                let mut raw = lifx_core::RawMessage::build(&opts, lifx_core::Message::GetService)?;
+                m.patch(&mut raw);
+                raw.pack()
             }
-        )
-    })
+        }
+    }
+
+    /// Decode a packet into the target it came from and its message. Packets for
+    /// another source are Ok(None), as they aren't replies to us.
+    pub fn unpack(&self, bytes: &[u8]) -> Result<Option<(u64, LifxMessage)>, lifx_core::Error> {
+        let raw = lifx_core::RawMessage::unpack(bytes)?;
+        if raw.frame.source != self.source {
+            return Ok(None);
+        }
+        let msg = LifxMessage::from_raw(&raw)?;
+        Ok(Some((raw.frame_addr.target, msg)))
+    }
 }
 
-macro_rules! send_bytes {
-    ($log_addr:expr, $sock:expr, $bytes:expr, $addr:expr) => {{
-        let res1 = $sock.send_to($bytes, $addr);
-        match res1 {
-            Ok(_) => {
-                // log_event!($log_addr, "event 1 success");
+impl Default for LifxPackets {
+    fn default() -> Self {
+        LifxPackets::new(LIFX_SOURCE)
+    }
+}
+
+/// Format a mac as d0:73:d5:01:02:03.
+pub fn format_target(target: &[u8; 6]) -> String {
+    target
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// A target as a log field, where none means every device that hears it.
+fn target_field(target: Option<u64>) -> String {
+    match target {
+        Some(t) => format_target(&u64_to_target(t)),
+        None => "all".to_string(),
+    }
+}
+
+/// Parse a mac written as d0:73:d5:01:02:03.
+pub fn parse_target(s: &str) -> Result<[u8; 6], String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 6 {
+        return Err(format!("Invalid target {}, expected 6 bytes", s));
+    }
+    let mut t = [0u8; 6];
+    for (b, p) in t.iter_mut().zip(parts.iter()) {
+        *b = u8::from_str_radix(p, 16).map_err(|_| format!("Invalid target {}", s))?;
+    }
+    Ok(t)
+}
+
+// Effects are tracked per device, not per address, since many devices can share
+// one address.
+type DeviceKey = (SocketAddr, Option<u64>);
+
+// How long to wait for a bulb to acknowledge a set, and how many more times to
+// send it before giving up. The plan refresh catches anything still missed.
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+const ACK_RETRIES: u32 = 2;
+
+// A set the bulb hasn't acknowledged yet.
+struct Unacked {
+    key: DeviceKey,
+    bytes: Vec<u8>,
+    sent: Instant,
+    retries: u32,
+}
+
+pub struct LifxController {
+    sock: UdpSocket,
+    packets: LifxPackets,
+    // The address each target was last sent to, so we can reject replies that
+    // claim to be a device but come from somewhere else.
+    expected: HashMap<u64, SocketAddr>,
+    // Who to pass decoded replies to.
+    subscriber: Option<Recipient<LifxReply>>,
+    // Pending effect timers per bulb, so a new colour can cancel a waveform that
+    // hasn't been sent yet rather than have it clobber the new state. Each timer is
+    // numbered, so it can take itself off once it has run.
+    effects: HashMap<DeviceKey, Vec<(u64, SpawnHandle)>>,
+    next_effect: u64,
+    // Sets waiting on an acknowledgement, by sequence number.
+    unacked: HashMap<u8, Unacked>,
+}
+
+impl LifxController {
+    pub fn new(bind: SocketAddr) -> std::io::Result<Self> {
+        let sock = UdpSocket::bind(bind)?;
+        // We poll for replies from the actor, so never block the mailbox on them.
+        sock.set_nonblocking(true)?;
+        sock.set_broadcast(true)?;
+
+        Ok(LifxController {
+            sock,
+            packets: LifxPackets::default(),
+            expected: HashMap::new(),
+            subscriber: None,
+            effects: HashMap::new(),
+            next_effect: 0,
+            unacked: HashMap::new(),
+        })
+    }
+
+    fn pack<M: Into<LifxMessage>>(
+        &mut self,
+        target: Option<u64>,
+        ack_required: bool,
+        msg: M,
+    ) -> Option<Vec<u8>> {
+        let msg = msg.into();
+        let packet = msg.typ();
+        match self.packets.pack(target, ack_required, msg) {
+            Ok(bytes) => {
+                trace!(
+                    packet,
+                    seq = self.packets.sequence(),
+                    target = %target_field(target),
+                    "Sending"
+                );
+                Some(bytes)
             }
             Err(e) => {
-                log_event!($log_addr, "Failed to send {}", e);
+                warn!(packet, "Failed to build message -> {:?}", e);
+                None
             }
-        };
-        // Send twice to be sure ...
-        let res2 = $sock.send_to($bytes, $addr);
-        match res2 {
-            Ok(_) => {
-                // log_event!($log_addr, "event 2 success");
+        }
+    }
+
+    fn send_now<M: Into<LifxMessage>>(&mut self, addr: SocketAddr, target: Option<u64>, msg: M) {
+        if let Some(t) = target {
+            self.expected.insert(t, addr);
+        }
+        if let Some(bytes) = self.pack(target, false, msg) {
+            send_bytes!(self.sock, &bytes, &addr);
+        }
+    }
+
+    // Send a set, and keep it to send again until the bulb acknowledges it.
+    fn send_acked<M: Into<LifxMessage>>(&mut self, addr: SocketAddr, target: Option<u64>, msg: M) {
+        if let Some(t) = target {
+            self.expected.insert(t, addr);
+        }
+        if let Some(bytes) = self.pack(target, target.is_some(), msg) {
+            let seq = self.packets.sequence();
+            self.send_tracked((addr, target), seq, bytes);
+        }
+    }
+
+    // Without a target there is no one bulb to wait on, so it just goes once.
+    fn send_tracked(&mut self, key: DeviceKey, seq: u8, bytes: Vec<u8>) {
+        send_bytes!(self.sock, &bytes, &key.0);
+        if key.1.is_none() {
+            return;
+        }
+        self.unacked.insert(
+            seq,
+            Unacked {
+                key,
+                bytes,
+                sent: Instant::now(),
+                retries: 0,
+            },
+        );
+    }
+
+    fn resend_unacked(&mut self) {
+        let now = Instant::now();
+        let sock = &self.sock;
+        self.unacked.retain(|seq, u| {
+            if now.duration_since(u.sent) < ACK_TIMEOUT {
+                return true;
+            }
+            if u.retries >= ACK_RETRIES {
+                debug!(addr = %u.key.0, seq, "Giving up on acknowledgement");
+                return false;
+            }
+            trace!(addr = %u.key.0, seq, "Resending unacknowledged");
+            send_bytes!(sock, &u.bytes, &u.key.0);
+            u.sent = now;
+            u.retries += 1;
+            true
+        });
+    }
+
+    fn cancel_effects(&mut self, key: &DeviceKey, ctx: &mut Context<Self>) {
+        if let Some(handles) = self.effects.remove(key) {
+            for (_, h) in handles {
+                ctx.cancel_future(h);
             }
+        }
+        // An older set sent again now would undo the new one.
+        self.unacked.retain(|_, u| u.key != *key);
+    }
+
+    // A timer has run, so there is nothing left of it to cancel.
+    fn effect_done(&mut self, key: &DeviceKey, id: u64) {
+        if let Some(handles) = self.effects.get_mut(key) {
+            handles.retain(|(i, _)| *i != id);
+            if handles.is_empty() {
+                self.effects.remove(key);
+            }
+        }
+    }
+
+    // Send the packet seq after delay, without blocking the mailbox, and wait on
+    // its acknowledgement from then.
+    fn send_later(
+        &mut self,
+        key: DeviceKey,
+        seq: u8,
+        bytes: Vec<u8>,
+        delay: Duration,
+        ctx: &mut Context<Self>,
+    ) {
+        let id = self.next_effect;
+        self.next_effect = self.next_effect.wrapping_add(1);
+        let h = ctx.run_later(delay, move |act, _ctx| {
+            act.send_tracked(key, seq, bytes);
+            act.effect_done(&key, id);
+        });
+        self.effects.entry(key).or_default().push((id, h));
+    }
+
+    fn recv_replies(&mut self) {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.sock.recv_from(&mut buf) {
+                Ok((len, addr)) => self.handle_packet(&buf[..len], addr),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to recv -> {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, bytes: &[u8], addr: SocketAddr) {
+        let (target, msg) = match self.packets.unpack(bytes) {
+            Ok(Some(r)) => r,
+            // Not a reply to us - probably another app, or our own broadcast.
+            Ok(None) => return,
             Err(e) => {
-                log_event!($log_addr, "Failed to send {}", e);
+                debug!(%addr, "Invalid reply -> {:?}", e);
+                return;
             }
         };
-    }};
+
+        if let LifxMessage::Core(lifx_core::Message::Acknowledgement { seq }) = msg {
+            if self.unacked.get(&seq).map(|u| u.key.0) == Some(addr) {
+                self.unacked.remove(&seq);
+            }
+        }
+
+        match self.expected.get(&target) {
+            Some(expect) if *expect != addr => {
+                warn!(
+                    %addr,
+                    target = %target_field(Some(target)),
+                    packet = msg.typ(),
+                    "Rejecting reply, expected it from {}",
+                    expect
+                );
+                return;
+            }
+            _ => {}
+        }
+
+        trace!(
+            %addr,
+            target = %target_field(Some(target)),
+            packet = msg.typ(),
+            "Reply {:?}",
+            msg
+        );
+        if let Some(sub) = &self.subscriber {
+            let _ = sub.do_send(LifxReply { addr, target, msg });
+        }
+    }
 }
 
-// The actual lifx control bits
+impl Actor for LifxController {
+    type Context = Context<Self>;
 
-pub struct LifxController {
-    sock: UdpSocket,
-    log_addr: actix::Addr<LogActor>,
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_millis(50), move |act, _ctx| {
+            act.recv_replies();
+            act.resend_unacked();
+        });
+    }
 }
 
-impl LifxController {
-    pub fn new(log_addr: actix::Addr<LogActor>) -> Self {
-        let sock = UdpSocket::bind("0.0.0.0:56701").unwrap();
+/// A decoded message from a device that was addressed to us.
+#[derive(Debug)]
+pub struct LifxReply {
+    pub addr: SocketAddr,
+    pub target: u64,
+    pub msg: LifxMessage,
+}
+
+impl Message for LifxReply {
+    type Result = ();
+}
+
+/// Ask the controller to pass all replies on to this recipient.
+pub struct LifxControllerSubscribe(pub Recipient<LifxReply>);
+
+impl Message for LifxControllerSubscribe {
+    type Result = ();
+}
+
+impl Handler<LifxControllerSubscribe> for LifxController {
+    type Result = ();
+
+    fn handle(&mut self, sub: LifxControllerSubscribe, _: &mut Context<Self>) -> Self::Result {
+        self.subscriber = Some(sub.0);
+    }
+}
+
+/// Send each of msgs to a device. Any replies go to the subscriber.
+#[derive(Debug)]
+struct LifxControllerQuery {
+    pub addr: SocketAddr,
+    pub target: Option<u64>,
+    pub msgs: Vec<LifxMessage>,
+}
+
+impl Message for LifxControllerQuery {
+    type Result = ();
+}
+
+impl Handler<LifxControllerQuery> for LifxController {
+    type Result = ();
 
-        LifxController {
-            sock: sock,
-            log_addr: log_addr,
+    fn handle(&mut self, event: LifxControllerQuery, _: &mut Context<Self>) -> Self::Result {
+        for msg in event.msgs {
+            self.send_now(event.addr, event.target, msg);
         }
     }
 }
 
-impl Actor for LifxController {
-    type Context = Context<Self>;
+/// Ask the device at addr who it is. It replies with StateService, which has the
+/// target in the header.
+#[derive(Debug)]
+struct LifxControllerDiscover {
+    pub addr: SocketAddr,
+}
+
+impl Message for LifxControllerDiscover {
+    type Result = ();
+}
+
+impl Handler<LifxControllerDiscover> for LifxController {
+    type Result = ();
+
+    fn handle(&mut self, event: LifxControllerDiscover, _: &mut Context<Self>) -> Self::Result {
+        self.send_now(event.addr, None, lifx_core::Message::GetService);
+    }
+}
+
+fn lifx_waveform(w: plans::LightWaveform) -> lifx_core::Waveform {
+    match w {
+        plans::LightWaveform::Saw => lifx_core::Waveform::Saw,
+        plans::LightWaveform::Sine => lifx_core::Waveform::Sine,
+        plans::LightWaveform::HalfSine => lifx_core::Waveform::HalfSign,
+        plans::LightWaveform::Triangle => lifx_core::Waveform::Triangle,
+        plans::LightWaveform::Pulse => lifx_core::Waveform::Pulse,
+    }
+}
+
+fn waveform_message(effect: &plans::LightEffect) -> lifx_core::Message {
+    if effect.brightness_only {
+        lifx_core::Message::SetWaveformOptional {
+            reserved: 0,
+            transient: effect.transient,
+            color: effect.colour,
+            period: effect.period,
+            cycles: effect.cycles,
+            skew_ratio: effect.skew_ratio,
+            waveform: lifx_waveform(effect.waveform),
+            set_hue: false,
+            set_saturation: false,
+            set_brightness: true,
+            set_kelvin: false,
+        }
+    } else {
+        lifx_core::Message::SetWaveform {
+            reserved: 0,
+            transient: effect.transient,
+            color: effect.colour,
+            period: effect.period,
+            cycles: effect.cycles,
+            skew_ratio: effect.skew_ratio,
+            waveform: lifx_waveform(effect.waveform),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct LifxControllerSetColour {
     pub addr: SocketAddr,
+    pub target: Option<u64>,
     pub duration: u32,
-    pub flicker: bool,
     pub colour: HSBK,
+    pub effect: Option<plans::LightEffect>,
+    pub infrared: Option<u16>,
 }
 
 impl Message for LifxControllerSetColour {
@@ -82,64 +509,204 @@ impl Message for LifxControllerSetColour {
 impl Handler<LifxControllerSetColour> for LifxController {
     type Result = ();
 
-    fn handle(&mut self, event: LifxControllerSetColour, _: &mut Context<Self>) -> Self::Result {
-        // Set the default lifx options. This could be good to cache in the struct?
-        log_event!(self.log_addr, "Change colour to: {:?}", event);
-
-        let opts = lifx_core::BuildOptions {
-            // target: Some(event.addr),
-            // source: 12345678,
-            ..Default::default()
-        };
-
-        let rawmsg = lifx_core::RawMessage::build(
-            &opts,
+    fn handle(&mut self, event: LifxControllerSetColour, ctx: &mut Context<Self>) -> Self::Result {
+        let span = debug_span!(
+            "set_colour",
+            addr = %event.addr,
+            target = %target_field(event.target)
+        );
+        let _enter = span.enter();
+        debug!("Change colour to: {:?}", event);
+
+        let key = (event.addr, event.target);
+        // Whatever was still scheduled for this bulb is now out of date.
+        self.cancel_effects(&key, ctx);
+
+        self.send_acked(
+            event.addr,
+            event.target,
             lifx_core::Message::LightSetColor {
                 reserved: 0,
                 color: event.colour,
                 duration: event.duration,
             },
-        )
-        .unwrap();
-        let raw_bytes = rawmsg.pack().unwrap();
-
-        // Always set once, even on flicker, to make sure it's the colour
-        send_bytes!(self.log_addr, self.sock, &raw_bytes, &(event.addr));
-
-        let mut rng = thread_rng();
-        let r = rng.gen_range(0, 6);
-
-        if event.flicker && r == 0 {
-            let flick_rawmsg = lifx_core::RawMessage::build(
-                &opts,
-                lifx_core::Message::LightSetColor {
-                    reserved: 0,
-                    color: HSBK {
-                        hue: 0,
-                        saturation: 0,
-                        brightness: 0,
-                        kelvin: 0,
+        );
+
+        if let Some(brightness) = event.infrared {
+            self.send_acked(
+                event.addr,
+                event.target,
+                lifx_core::Message::LightSetInfrared { brightness },
+            );
+        }
+
+        // Hand the effect to the bulb to render. The set above is the colour it swings
+        // away from, and returns to when transient.
+        if let Some(effect) = event.effect {
+            let ack = event.target.is_some();
+            if let Some(wave_bytes) = self.pack(event.target, ack, waveform_message(&effect)) {
+                let seq = self.packets.sequence();
+                // Give the colour transition a moment to land first.
+                self.send_later(
+                    key,
+                    seq,
+                    wave_bytes,
+                    Duration::from_millis(event.duration as u64),
+                    ctx,
+                );
+            }
+        }
+    }
+}
+
+// Restart the effect a bulb should be running, without setting its colour first.
+// A set colour stops any waveform, so this is how a running effect is refreshed.
+#[derive(Debug)]
+struct LifxControllerSetWaveform {
+    pub addr: SocketAddr,
+    pub target: Option<u64>,
+    pub effect: plans::LightEffect,
+}
+
+impl Message for LifxControllerSetWaveform {
+    type Result = ();
+}
+
+impl Handler<LifxControllerSetWaveform> for LifxController {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        event: LifxControllerSetWaveform,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        debug!(addr = %event.addr, target = %target_field(event.target), "Refresh effect");
+        self.cancel_effects(&(event.addr, event.target), ctx);
+        self.send_acked(event.addr, event.target, waveform_message(&event.effect));
+    }
+}
+
+// Group neighbouring zones of the same colour into (start, end, colour).
+fn zone_runs(zones: &[HSBK]) -> Vec<(usize, usize, HSBK)> {
+    let mut runs: Vec<(usize, usize, HSBK)> = Vec::new();
+    for (i, z) in zones.iter().enumerate() {
+        match runs.last_mut() {
+            Some(r) if r.2 == *z => r.1 = i,
+            _ => runs.push((i, i, *z)),
+        }
+    }
+    runs
+}
+
+/// Set every zone of a multizone device, the first zone first.
+#[derive(Debug)]
+struct LifxControllerSetZones {
+    pub addr: SocketAddr,
+    pub target: Option<u64>,
+    pub duration: u32,
+    pub zones: Vec<HSBK>,
+    // Whether the device understands SetExtendedColorZones.
+    pub extended: bool,
+}
+
+impl Message for LifxControllerSetZones {
+    type Result = ();
+}
+
+impl Handler<LifxControllerSetZones> for LifxController {
+    type Result = ();
+
+    fn handle(&mut self, event: LifxControllerSetZones, ctx: &mut Context<Self>) -> Self::Result {
+        let span = debug_span!(
+            "set_zones",
+            addr = %event.addr,
+            target = %target_field(event.target)
+        );
+        let _enter = span.enter();
+        debug!("Change zones to: {:?}", event);
+        self.cancel_effects(&(event.addr, event.target), ctx);
+
+        if event.extended {
+            for (i, chunk) in event.zones.chunks(EXTENDED_ZONES).enumerate() {
+                self.send_acked(
+                    event.addr,
+                    event.target,
+                    ExtMessage::SetExtendedColorZones {
+                        duration: event.duration,
+                        apply: 1,
+                        index: (i * EXTENDED_ZONES) as u16,
+                        colors: chunk.to_vec(),
+                    },
+                );
+            }
+        } else {
+            // Older firmware takes a range at a time, so queue each run of colour
+            // and have the last one apply them all together.
+            let runs = zone_runs(&event.zones);
+            let last = runs.len().saturating_sub(1);
+            for (i, (start, end, colour)) in runs.into_iter().enumerate() {
+                self.send_acked(
+                    event.addr,
+                    event.target,
+                    lifx_core::Message::SetColorZones {
+                        start_index: start as u8,
+                        end_index: end as u8,
+                        color: colour,
+                        duration: event.duration,
+                        apply: if i == last {
+                            lifx_core::ApplicationRequest::Apply
+                        } else {
+                            lifx_core::ApplicationRequest::NoApply
+                        },
                     },
+                );
+            }
+        }
+    }
+}
+
+/// Set every pixel of each tile in a matrix device's chain.
+#[derive(Debug)]
+struct LifxControllerSetTiles {
+    pub addr: SocketAddr,
+    pub target: Option<u64>,
+    pub duration: u32,
+    pub tiles: Vec<TileState>,
+}
+
+impl Message for LifxControllerSetTiles {
+    type Result = ();
+}
+
+impl Handler<LifxControllerSetTiles> for LifxController {
+    type Result = ();
+
+    fn handle(&mut self, event: LifxControllerSetTiles, ctx: &mut Context<Self>) -> Self::Result {
+        let span = debug_span!(
+            "set_tiles",
+            addr = %event.addr,
+            target = %target_field(event.target)
+        );
+        let _enter = span.enter();
+        debug!("Change tiles to: {:?}", event);
+        self.cancel_effects(&(event.addr, event.target), ctx);
+
+        // Tiles are 8x8 and Candles 5x6, so one Set64 covers a whole tile.
+        for (i, tile) in event.tiles.iter().enumerate() {
+            self.send_acked(
+                event.addr,
+                event.target,
+                ExtMessage::Set64 {
+                    tile_index: i as u8,
+                    length: 1,
+                    x: 0,
+                    y: 0,
+                    width: tile.width,
                     duration: event.duration,
+                    colors: tile.pixels.iter().take(TILE_PIXELS).cloned().collect(),
                 },
-            )
-            .unwrap();
-            let flick_bytes = flick_rawmsg.pack().unwrap();
-
-            for _i in 0..rng.gen_range(1, 4) {
-                use std::thread;
-                {
-                    let d = rng.gen_range(0, 6);
-                    thread::sleep(Duration::from_millis(d * 25));
-                    send_bytes!(self.log_addr, self.sock, &flick_bytes, &(event.addr));
-                }
-                {
-                    let d = rng.gen_range(0, 6);
-                    thread::sleep(Duration::from_millis(d * 25));
-                    send_bytes!(self.log_addr, self.sock, &raw_bytes, &(event.addr));
-                }
-            } // end for
-        } // end flicker
+            );
+        }
     }
 }
 
@@ -157,14 +724,122 @@ pub struct LightBulbStatus {
     pub current: HSBK,
     pub plan: String,
     pub last_event: String,
+    pub target: Option<String>,
+    pub info: DeviceInfo,
+    pub product: Option<String>,
+    pub min_kelvin: u16,
+    pub max_kelvin: u16,
+    pub warning: Option<String>,
+    pub zones: Vec<HSBK>,
+    pub tiles: Vec<TileState>,
+    pub infrared: Option<u16>,
+    pub hev: Option<HevStatus>,
+    pub room: Option<String>,
+    // Whether anyone is in the room, once we've heard either way.
+    pub occupied: Option<bool>,
+}
+
+/// What a device has told us about itself.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub label: Option<String>,
+    pub vendor: Option<u32>,
+    pub product: Option<u32>,
+    pub firmware: Option<String>,
+    pub group: Option<String>,
+    pub location: Option<String>,
+}
+
+impl DeviceInfo {
+    fn known_product(&self) -> Option<&'static ProductInfo> {
+        match (self.vendor, self.product) {
+            (Some(v), Some(p)) => products::lookup(v, p),
+            _ => None,
+        }
+    }
+
+    /// What the device can do. Until we know, assume it's a typical colour bulb.
+    pub fn capabilities(&self) -> &'static ProductInfo {
+        self.known_product().unwrap_or(&products::UNKNOWN)
+    }
+
+    fn update(&mut self, msg: &lifx_core::Message) {
+        match msg {
+            lifx_core::Message::StateLabel { label } => {
+                self.label = Some(label.to_string());
+            }
+            lifx_core::Message::StateVersion {
+                vendor, product, ..
+            } => {
+                self.vendor = Some(*vendor);
+                self.product = Some(*product);
+            }
+            lifx_core::Message::StateHostFirmware { version, .. } => {
+                self.firmware = Some(format!("{}.{}", version >> 16, version & 0xffff));
+            }
+            lifx_core::Message::StateGroup { label, .. } => {
+                self.group = Some(label.to_string());
+            }
+            lifx_core::Message::StateLocation { label, .. } => {
+                self.location = Some(label.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    // Strips on firmware 2.77 or later take every zone in one message.
+    fn extended_multizone(&self) -> bool {
+        self.firmware
+            .as_ref()
+            .and_then(|f| {
+                let mut v = f.split('.').map(|p| p.parse::<u32>().ok());
+                Some((v.next()??, v.next()??))
+            })
+            .map(|v| v >= (2, 77))
+            .unwrap_or(false)
+    }
+}
+
+/// The clean cycle state of a HEV device.
+#[derive(Debug, Clone, Default)]
+pub struct HevStatus {
+    // Seconds, where remaining is 0 when no cycle is running.
+    pub duration: u32,
+    pub remaining: u32,
+    pub last_result: Option<HevResult>,
+}
+
+/// One tile of a matrix device, and what each pixel is showing in rows from the
+/// top left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileState {
+    pub width: u8,
+    pub height: u8,
+    pub pixels: Vec<HSBK>,
 }
 
 #[derive(Debug)]
+pub enum BulbError {
+    NotFound,
+    // The bulb can't do what was asked of it.
+    Unsupported(String),
+}
+
+#[derive(Debug, PartialEq)]
 pub struct LightBulb {
     name: String,
     addr: SocketAddr,
+    // The device mac, which goes in the header so only that device acts on the
+    // packet. Learnt from discovery if not configured.
+    target: Option<[u8; 6]>,
+    // What the bulb can do, if configured. Otherwise it's learnt from the bulb.
+    product: Option<&'static ProductInfo>,
     default_plan: plans::LightPlan,
     party_plan: plans::LightPlan,
+    // When to run a clean cycle, for bulbs that can.
+    clean: Option<plans::CleanSchedule>,
+    // Where the bulb is, for plans that follow whether anyone is there.
+    room: Option<String>,
 }
 
 impl LightBulb {
@@ -177,73 +852,687 @@ impl LightBulb {
         LightBulb {
             name,
             addr,
+            target: None,
+            product: None,
             default_plan,
             party_plan,
+            clean: None,
+            room: None,
         }
     }
-}
 
-// Logs
+    /// Address this bulb by mac, rather than waiting for discovery.
+    pub fn with_target(mut self, target: [u8; 6]) -> Self {
+        self.target = Some(target);
+        self
+    }
 
-pub struct LogEvent {
-    pub msg: String,
-}
+    /// Use the capabilities of this lifx product id, rather than asking the bulb.
+    pub fn with_product(mut self, product: u32) -> Self {
+        self.product = products::lookup(1, product);
+        if self.product.is_none() {
+            warn!(
+                "Unknown product {} for {}, asking the bulb instead",
+                product, self.name
+            );
+        }
+        self
+    }
 
-impl Message for LogEvent {
-    type Result = ();
-}
+    /// Run a HEV clean cycle every day, if the bulb turns out to be able to.
+    pub fn with_clean_schedule(mut self, clean: plans::CleanSchedule) -> Self {
+        self.clean = Some(clean);
+        self
+    }
 
-pub struct LogActor {}
+    /// Follow presence reports for this room.
+    pub fn with_room(mut self, room: &str) -> Self {
+        self.room = Some(room.to_string());
+        self
+    }
 
-impl Actor for LogActor {
-    type Context = Context<Self>;
+    fn target_u64(&self) -> Option<u64> {
+        self.target.as_ref().map(target_to_u64)
+    }
 }
 
-impl Handler<LogEvent> for LogActor {
-    type Result = ();
-
-    fn handle(&mut self, event: LogEvent, _: &mut Context<Self>) -> Self::Result {
-        debug!("{}", event.msg);
-    }
+/// Whether anyone is in a room, and since when.
+#[derive(Debug, Clone, Copy)]
+pub struct PresenceState {
+    pub occupied: bool,
+    pub since: DateTime<Tz>,
 }
 
+// Even if the plan output hasn't changed, re-send it this often in case the bulb
+// was power cycled or missed the packet.
+const REFRESH_SECS: i64 = 300;
+// A bulb that hasn't answered anything for this long is offline. Bulbs are asked
+// about themselves every five minutes, so this is three missed rounds.
+const OFFLINE_SECS: i64 = 900;
+
 struct LightBulbState {
     bulb: LightBulb,
     plan: plans::LightPlan,
-    last_event: time::Tm,
+    // When the plan should next be consulted.
+    last_event: DateTime<Tz>,
+    // When we last actually sent a colour to the bulb.
+    last_sent: DateTime<Tz>,
     current: HSBK,
+    // The effect the bulb is currently running, if any.
+    effect: Option<plans::LightEffect>,
+    info: DeviceInfo,
+    // Why the last shift couldn't be shown as the plan asked.
+    warning: Option<String>,
+    // What each zone is showing, if the device has them. The device tells us how
+    // many there are.
+    zones: Vec<HSBK>,
+    // Likewise for the tiles of a matrix device.
+    tiles: Vec<TileState>,
+    // The infrared level, for bulbs that have it.
+    infrared: Option<u16>,
+    hev: Option<HevStatus>,
+    // When we last started a scheduled clean.
+    last_clean: Option<DateTime<Tz>>,
+    // A clean asked for before we knew whether the bulb can, to start or stop once
+    // its version comes back.
+    pending_clean: Option<Option<u32>>,
+    // When we last heard from the bulb, or when it was registered if never.
+    last_seen: DateTime<Tz>,
+    online: bool,
 }
 
 impl LightBulbState {
-    pub fn status(&self) -> LightBulbStatus {
+    pub fn status(&self, presence: &HashMap<String, PresenceState>) -> LightBulbStatus {
         LightBulbStatus {
             name: self.bulb.name.clone(),
-            current: self.current.clone(),
+            current: self.current,
+            plan: self.plan.to_string(),
+            last_event: self.last_event.to_rfc3339(),
+            target: self.bulb.target.as_ref().map(format_target),
+            info: self.info.clone(),
+            product: self
+                .bulb
+                .product
+                .or_else(|| self.info.known_product())
+                .map(|p| p.name.to_string()),
+            min_kelvin: self.capabilities().min_kelvin,
+            max_kelvin: self.capabilities().max_kelvin,
+            warning: self.warning.clone(),
+            zones: self.zones.clone(),
+            tiles: self.tiles.clone(),
+            infrared: self.infrared,
+            hev: self.hev.clone(),
+            room: self.bulb.room.clone(),
+            occupied: self.presence(presence).map(|p| p.occupied),
+        }
+    }
+
+    fn presence<'a>(
+        &self,
+        presence: &'a HashMap<String, PresenceState>,
+    ) -> Option<&'a PresenceState> {
+        self.bulb.room.as_ref().and_then(|r| presence.get(r))
+    }
+
+    // Put the bulb on a plan, giving what it was and is now for the audit log,
+    // and the webhook event if that changed anything.
+    fn change_plan(
+        &mut self,
+        plan: plans::LightPlan,
+        colour: Option<HSBK>,
+    ) -> (BulbChange, Option<StateEvent>) {
+        let before = self.audit_state();
+        self.plan = plan;
+        // Make it change ASAP
+        self.last_event = clock::epoch();
+        let after = BulbState {
+            plan: self.plan.to_string(),
+            colour: colour.map(Into::into),
+        };
+        let event = if before.plan != after.plan {
+            info!(
+                bulb = %self.bulb.name,
+                from = %before.plan,
+                to = %after.plan,
+                "Plan changed"
+            );
+            Some(StateEvent::PlanChanged {
+                bulb: self.bulb.name.clone(),
+                from: before.plan.clone(),
+                to: after.plan.clone(),
+            })
+        } else {
+            None
+        };
+        let change = BulbChange {
+            bulb: self.bulb.name.clone(),
+            before,
+            after,
+        };
+        (change, event)
+    }
+
+    fn audit_state(&self) -> BulbState {
+        BulbState {
             plan: self.plan.to_string(),
-            last_event: self.last_event.to_local().rfc3339().to_string(),
+            colour: Some(self.current.into()),
+        }
+    }
+
+    // For commands that don't change the plan.
+    fn unchanged(&self) -> BulbChange {
+        BulbChange {
+            bulb: self.bulb.name.clone(),
+            before: self.audit_state(),
+            after: self.audit_state(),
+        }
+    }
+
+    fn capabilities(&self) -> &'static ProductInfo {
+        self.bulb
+            .product
+            .unwrap_or_else(|| self.info.capabilities())
+    }
+
+    // What the bulb can do, if it has been configured or has told us yet.
+    fn known_capabilities(&self) -> Option<&'static ProductInfo> {
+        self.bulb.product.or_else(|| self.info.known_product())
+    }
+
+    // Fit a shift to what the bulb can do, noting when that changed it.
+    fn fit(
+        &mut self,
+        lshift: &plans::LightShift,
+    ) -> (HSBK, Option<plans::LightEffect>, Option<u16>) {
+        let caps = self.capabilities();
+        let (colour, mut warning) = caps.fit(&lshift.colour);
+        let infrared = match lshift.infrared {
+            Some(_) if !caps.infrared => {
+                warning = warning.or_else(|| Some(format!("{} has no infrared", caps.name)));
+                None
+            }
+            ir => ir,
+        };
+        let effect = lshift.effect.map(|mut e| {
+            let (c, w) = caps.fit(&e.colour);
+            e.colour = c;
+            warning = warning.take().or(w);
+            e
+        });
+
+        self.note_warning(warning);
+        (colour, effect, infrared)
+    }
+
+    fn fit_each(&mut self, colours: &[HSBK]) -> Vec<HSBK> {
+        let caps = self.capabilities();
+        let mut warning = None;
+        let fitted = colours
+            .iter()
+            .map(|z| {
+                let (c, w) = caps.fit(z);
+                warning = warning.take().or(w);
+                c
+            })
+            .collect();
+        self.note_warning(warning);
+        fitted
+    }
+
+    fn note_warning(&mut self, warning: Option<String>) {
+        if warning != self.warning {
+            if let Some(w) = &warning {
+                warn!("{} plan {} -> {}", self.bulb.name, self.plan.to_string(), w);
+            }
+            self.warning = warning;
         }
     }
+
+    // Track the zones a strip reports, which is also how we learn how many it has.
+    fn update_zones(&mut self, msg: &lifx_core::Message) {
+        let (count, index, colours) = match msg {
+            lifx_core::Message::StateZone {
+                count,
+                index,
+                color,
+            } => (*count, *index, vec![*color]),
+            lifx_core::Message::StateMultiZone {
+                count,
+                index,
+                color0,
+                color1,
+                color2,
+                color3,
+                color4,
+                color5,
+                color6,
+                color7,
+            } => (
+                *count,
+                *index,
+                vec![
+                    *color0, *color1, *color2, *color3, *color4, *color5, *color6, *color7,
+                ],
+            ),
+            _ => return,
+        };
+        self.zones.resize(count as usize, self.current);
+        self.zones
+            .iter_mut()
+            .skip(index as usize)
+            .zip(colours)
+            .for_each(|(z, c)| *z = c);
+    }
+
+    fn update_ext(&mut self, msg: &ExtMessage) {
+        match msg {
+            ExtMessage::StateHevCycle {
+                duration_s,
+                remaining_s,
+                ..
+            } => {
+                let hev = self.hev.get_or_insert_with(HevStatus::default);
+                hev.duration = *duration_s;
+                hev.remaining = *remaining_s;
+            }
+            ExtMessage::StateLastHevCycleResult { result } => {
+                self.hev.get_or_insert_with(HevStatus::default).last_result = Some(*result);
+            }
+            msg => self.update_tiles(msg),
+        }
+    }
+
+    // Learn the shape of a matrix device's chain.
+    fn update_tiles(&mut self, msg: &ExtMessage) {
+        if let ExtMessage::StateDeviceChain {
+            start_index: 0,
+            tiles,
+        } = msg
+        {
+            let same = tiles.len() == self.tiles.len()
+                && tiles
+                    .iter()
+                    .zip(self.tiles.iter())
+                    .all(|(a, b)| a.width == b.width && a.height == b.height);
+            if !same {
+                let current = self.current;
+                self.tiles = tiles
+                    .iter()
+                    .map(|t| TileState {
+                        width: t.width,
+                        height: t.height,
+                        pixels: vec![current; t.width as usize * t.height as usize],
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    // How to ask the device about its zones or tiles, if it has them.
+    fn shape_query(&self) -> Option<LifxMessage> {
+        let caps = self.capabilities();
+        if caps.multizone {
+            Some(GET_ALL_ZONES.into())
+        } else if caps.matrix {
+            Some(ExtMessage::GetDeviceChain.into())
+        } else {
+            None
+        }
+    }
+
+    // The next frame for every tile, if the plan has frames and this device has
+    // tiles.
+    fn shift_tiles(
+        &mut self,
+        ts: DateTime<Tz>,
+        rng: &mut dyn rand::RngCore,
+    ) -> Option<(Vec<TileState>, u32)> {
+        if self.tiles.is_empty() {
+            return None;
+        }
+        let plan = self.plan;
+        let shapes: Vec<(u8, u8)> = self.tiles.iter().map(|t| (t.width, t.height)).collect();
+        let frames = shapes
+            .iter()
+            .map(|(w, h)| plan.shift_frame_with(ts, *w as usize, *h as usize, &mut *rng))
+            .collect::<Option<Vec<_>>>()?;
+
+        let duration = frames.iter().map(|f| f.duration).max().unwrap_or(0);
+        let tiles = shapes
+            .into_iter()
+            .zip(frames.iter())
+            .map(|((width, height), f)| TileState {
+                width,
+                height,
+                pixels: self.fit_each(&f.pixels),
+            })
+            .collect();
+        Some((tiles, duration))
+    }
+
+    fn set_all(&mut self, colour: HSBK) {
+        self.current = colour;
+        self.zones.iter_mut().for_each(|z| *z = colour);
+        self.tiles
+            .iter_mut()
+            .flat_map(|t| t.pixels.iter_mut())
+            .for_each(|p| *p = colour);
+    }
+
+    fn showing_all(&self, colour: &HSBK) -> bool {
+        self.current == *colour
+            && self.zones.iter().all(|z| z == colour)
+            && self
+                .tiles
+                .iter()
+                .flat_map(|t| t.pixels.iter())
+                .all(|p| p == colour)
+    }
+
+    // Does a reply from this address and target belong to this bulb? Until its
+    // target is known, from config or discovery, nothing can be said to be.
+    fn is_device(&self, addr: &SocketAddr, target: u64) -> bool {
+        self.bulb.addr == *addr && self.bulb.target_u64() == Some(target)
+    }
 }
 
 pub struct LightManager {
     bulbs: Vec<LightBulbState>,
-    log_addr: actix::Addr<LogActor>,
     lifx: actix::Addr<LifxController>,
+    // When a party started with a time limit should end.
+    party_until: Option<DateTime<Tz>>,
+    clock: Arc<dyn Clock>,
+    // Shared by every plan, so a seeded one replays the same.
+    rng: SharedRng,
+    // Who is where, by room.
+    presence: HashMap<String, PresenceState>,
+    webhooks: Option<actix::Addr<WebhookActor>>,
+    audit: Option<actix::Addr<AuditActor>>,
 }
 
 impl LightManager {
-    pub fn new(log_addr: actix::Addr<LogActor>, lifx: actix::Addr<LifxController>) -> Self {
+    pub fn new(lifx: actix::Addr<LifxController>) -> Self {
         // Init all the light plans and attach them here?
         LightManager {
-            log_addr: log_addr,
             bulbs: Vec::new(),
-            lifx: lifx,
+            lifx,
+            party_until: None,
+            clock: Arc::new(SystemClock::new(Tz::UTC)),
+            rng: clock::system_rng(),
+            presence: HashMap::new(),
+            webhooks: None,
+            audit: None,
         }
     }
+
+    /// Without this the system clock is read in utc.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_rng(mut self, rng: SharedRng) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Tell these webhooks about plan changes, parties and bulbs going offline.
+    pub fn with_webhooks(mut self, webhooks: actix::Addr<WebhookActor>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Record who changed what here.
+    pub fn with_audit(mut self, audit: actix::Addr<AuditActor>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    fn audit(&self, command: &str, source: Source, detail: Option<String>, bulbs: Vec<BulbChange>) {
+        if let Some(audit) = &self.audit {
+            audit.do_send(AuditRecord(AuditEntry {
+                at: self.clock.now().to_rfc3339(),
+                command: command.to_string(),
+                source,
+                detail,
+                bulbs,
+            }));
+        }
+    }
+
+    fn notify(&self, events: Vec<StateEvent>) {
+        if let Some(webhooks) = &self.webhooks {
+            let at = self.clock.now();
+            for event in events {
+                webhooks.do_send(WebhookNotify { at, event });
+            }
+        }
+    }
+
+    // Start or stop a clean cycle, then ask how it went.
+    fn clean(&self, b: &LightBulbState, duration: Option<u32>) {
+        self.lifx.do_send(clean_query(b, duration));
+    }
+
+    fn start_due_cleans(&mut self, now: DateTime<Tz>) {
+        for i in 0..self.bulbs.len() {
+            let b = &self.bulbs[i];
+            let schedule = match b.bulb.clean {
+                Some(c) if b.capabilities().hev && c.due(&now, b.last_clean.as_ref()) => c,
+                _ => continue,
+            };
+            info!(bulb = %b.bulb.name, "Starting scheduled clean");
+            self.clean(b, Some(schedule.duration));
+            self.audit(
+                "clean_start",
+                Source::Scheduler,
+                Some(format!("for {}s", schedule.duration)),
+                vec![b.unchanged()],
+            );
+            self.bulbs[i].last_clean = Some(now);
+        }
+    }
+
+    fn end_party(&mut self, source: Source) {
+        self.party_until = None;
+        let (changes, events): (Vec<_>, Vec<_>) = self
+            .bulbs
+            .iter_mut()
+            .map(|bstate| {
+                let plan = bstate.bulb.default_plan;
+                bstate.change_plan(plan, None)
+            })
+            .unzip();
+        let mut events: Vec<StateEvent> = events.into_iter().flatten().collect();
+        // The daily check ends the party whether or not there is one, so only
+        // record it when something changed. Anyone asking is always recorded.
+        let changed = !events.is_empty();
+        if changed {
+            events.insert(0, StateEvent::PartyEnded);
+        }
+        if changed || source != Source::Scheduler {
+            self.audit("party_end", source, None, changes);
+        }
+        self.notify(events);
+    }
 }
 
 impl Actor for LightManager {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.lifx
+            .do_send(LifxControllerSubscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<LifxReply> for LightManager {
+    type Result = ();
+
+    fn handle(&mut self, reply: LifxReply, _ctx: &mut Context<Self>) -> Self::Result {
+        match &reply.msg {
+            LifxMessage::Core(lifx_core::Message::StateService { .. }) => {
+                let target = u64_to_target(reply.target);
+                // A mac belongs to one bulb. If a bulb already has it, this is that
+                // bulb answering, not a new one to learn.
+                if self.bulbs.iter().any(|b| b.bulb.target == Some(target)) {
+                    return;
+                }
+                if let Some(b) = self
+                    .bulbs
+                    .iter_mut()
+                    .find(|b| b.bulb.addr == reply.addr && b.bulb.target.is_none())
+                {
+                    info!(
+                        bulb = %b.bulb.name,
+                        target = %format_target(&target),
+                        "Learnt target"
+                    );
+                    b.bulb.target = Some(target);
+                }
+            }
+            msg => {
+                let t_now = self.clock.now();
+                let mut online = Vec::new();
+                let lifx = &self.lifx;
+                self.bulbs
+                    .iter_mut()
+                    .filter(|b| b.is_device(&reply.addr, reply.target))
+                    .for_each(|b| {
+                        b.last_seen = t_now;
+                        if !b.online {
+                            info!(bulb = %b.bulb.name, "Online");
+                            b.online = true;
+                            online.push(StateEvent::BulbOnline {
+                                bulb: b.bulb.name.clone(),
+                            });
+                        }
+                        match msg {
+                            LifxMessage::Core(lifx_core::Message::LightStateInfrared {
+                                brightness,
+                            }) => b.infrared = Some(*brightness),
+                            LifxMessage::Core(m) => {
+                                b.info.update(m);
+                                b.update_zones(m);
+                            }
+                            LifxMessage::Ext(m) => b.update_ext(m),
+                        }
+                        // Now we know what it is, find out its shape.
+                        if let LifxMessage::Core(lifx_core::Message::StateVersion { .. }) = msg {
+                            if let Some(duration) = b.pending_clean.take() {
+                                let caps = b.capabilities();
+                                if caps.hev {
+                                    lifx.do_send(clean_query(b, duration));
+                                } else {
+                                    warn!(
+                                        bulb = %b.bulb.name,
+                                        product = %caps.name,
+                                        "Dropping clean, the bulb can't"
+                                    );
+                                }
+                            }
+                            if b.zones.is_empty() && b.tiles.is_empty() {
+                                if let Some(q) = b.shape_query() {
+                                    lifx.do_send(LifxControllerQuery {
+                                        addr: b.bulb.addr,
+                                        target: b.bulb.target_u64(),
+                                        msgs: vec![q],
+                                    });
+                                }
+                            }
+                        }
+                    });
+                self.notify(online);
+            }
+        }
+    }
+}
+
+fn clean_query(b: &LightBulbState, duration: Option<u32>) -> LifxControllerQuery {
+    LifxControllerQuery {
+        addr: b.bulb.addr,
+        target: b.bulb.target_u64(),
+        msgs: vec![
+            ExtMessage::SetHevCycle {
+                enable: duration.is_some(),
+                duration_s: duration.unwrap_or(0),
+            }
+            .into(),
+            ExtMessage::GetHevCycle.into(),
+        ],
+    }
+}
+
+const GET_ALL_ZONES: lifx_core::Message = lifx_core::Message::GetColorZones {
+    start_index: 0,
+    end_index: 255,
+};
+
+pub struct LightManagerQueryInfo;
+
+impl Message for LightManagerQueryInfo {
+    type Result = ();
+}
+
+impl Handler<LightManagerQueryInfo> for LightManager {
+    type Result = ();
+
+    fn handle(&mut self, _req: LightManagerQueryInfo, _ctx: &mut Context<Self>) -> Self::Result {
+        let t_now = self.clock.now();
+        let mut offline = Vec::new();
+        for b in self.bulbs.iter_mut() {
+            if b.online && t_now - b.last_seen >= ChronoDuration::seconds(OFFLINE_SECS) {
+                info!(bulb = %b.bulb.name, last_seen = %b.last_seen, "Offline");
+                b.online = false;
+                offline.push(StateEvent::BulbOffline {
+                    bulb: b.bulb.name.clone(),
+                    last_seen: b.last_seen.to_rfc3339(),
+                });
+            }
+        }
+        self.notify(offline);
+
+        for b in self.bulbs.iter() {
+            let mut msgs: Vec<LifxMessage> = vec![
+                lifx_core::Message::GetLabel.into(),
+                lifx_core::Message::GetVersion.into(),
+                lifx_core::Message::GetHostFirmware.into(),
+                lifx_core::Message::GetGroup.into(),
+                lifx_core::Message::GetLocation.into(),
+            ];
+            msgs.extend(b.shape_query());
+            if b.capabilities().infrared {
+                msgs.push(lifx_core::Message::LightGetInfrared.into());
+            }
+            if b.capabilities().hev {
+                msgs.push(ExtMessage::GetHevCycle.into());
+                msgs.push(ExtMessage::GetLastHevCycleResult.into());
+            }
+            self.lifx.do_send(LifxControllerQuery {
+                addr: b.bulb.addr,
+                target: b.bulb.target_u64(),
+                msgs,
+            });
+        }
+    }
+}
+
+pub struct LightManagerDiscover;
+
+impl Message for LightManagerDiscover {
+    type Result = ();
+}
+
+impl Handler<LightManagerDiscover> for LightManager {
+    type Result = ();
+
+    fn handle(&mut self, _req: LightManagerDiscover, _ctx: &mut Context<Self>) -> Self::Result {
+        // Unicast rather than broadcast, so this works through a router.
+        for b in self.bulbs.iter().filter(|b| b.bulb.target.is_none()) {
+            self.lifx
+                .do_send(LifxControllerDiscover { addr: b.bulb.addr });
+        }
+    }
 }
 
 pub struct LightManagerRegister(pub LightBulb);
@@ -256,20 +1545,32 @@ impl Handler<LightManagerRegister> for LightManager {
     type Result = Result<(), ()>;
 
     fn handle(&mut self, reg: LightManagerRegister, _: &mut Context<Self>) -> Self::Result {
-        log_event!(self.log_addr, "Registered {}", reg.0.name);
+        debug!(bulb = %reg.0.name, plan = %reg.0.default_plan.to_string(), "Registered");
 
-        let plan = reg.0.default_plan.clone();
+        let plan = reg.0.default_plan;
 
         self.bulbs.push(LightBulbState {
             bulb: reg.0,
-            plan: plan,
-            last_event: time::empty_tm(),
+            plan,
+            last_event: clock::epoch(),
+            last_sent: clock::epoch(),
             current: HSBK {
                 hue: 0,
                 saturation: 0,
                 brightness: 0,
                 kelvin: 0,
             },
+            effect: None,
+            info: DeviceInfo::default(),
+            warning: None,
+            zones: Vec::new(),
+            tiles: Vec::new(),
+            infrared: None,
+            hev: None,
+            last_clean: None,
+            pending_clean: None,
+            last_seen: self.clock.now(),
+            online: true,
         });
 
         Ok(())
@@ -286,16 +1587,12 @@ impl Handler<LightManagerStatus> for LightManager {
     type Result = Result<Vec<LightBulbStatus>, ()>;
 
     fn handle(&mut self, _req: LightManagerStatus, _ctx: &mut Context<Self>) -> Self::Result {
-        log_event!(self.log_addr, "Status req");
         let status: Vec<LightBulbStatus> = self
             .bulbs
             .iter()
-            .map(|b| {
-                let s = b.status();
-                log_event!(self.log_addr, "status inner: {:?}", s);
-                s
-            })
+            .map(|b| b.status(&self.presence))
             .collect();
+        trace!("Status {:?}", status);
 
         Ok(status)
     }
@@ -311,38 +1608,152 @@ impl Handler<LightManagerShift> for LightManager {
     type Result = ();
 
     fn handle(&mut self, _req: LightManagerShift, _ctx: &mut Context<Self>) -> Self::Result {
+        let t_now = self.clock.now();
+
+        if let Some(until) = self.party_until {
+            if t_now >= until {
+                info!("Party time is up");
+                self.end_party(Source::Scheduler);
+            }
+        }
+
+        self.start_due_cleans(t_now);
+
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        let presence = &self.presence;
         for b in self.bulbs.iter_mut() {
-            let t_now = time::now();
+            let span = debug_span!("bulb", bulb = %b.bulb.name, plan = %b.plan.to_string());
+            let _enter = span.enter();
+
+            // An empty room goes to the plan's vacant shift once it has been empty
+            // long enough, until then we wake up in time to change.
+            let empty_for = b
+                .presence(presence)
+                .filter(|p| !p.occupied)
+                .map(|p| t_now - p.since);
+            let (vacant, vacant_in) = match (b.plan.vacant(), empty_for) {
+                (Some((after, vshift)), Some(empty)) if empty >= after => (Some(vshift), None),
+                (Some((after, _)), Some(empty)) => (None, Some(after - empty)),
+                _ => (None, None),
+            };
 
-            let shift = if t_now > b.last_event {
-                b.plan.shift(t_now)
-            } else {
+            let dimmed = vacant.is_some();
+            let shift = if t_now <= b.last_event {
                 None
+            } else if dimmed {
+                vacant
+            } else {
+                b.plan.shift_with(t_now, &mut *rng)
             };
 
             match shift {
                 Some(lshift) => {
-                    log_event!(self.log_addr, "Shift requested to {:?}", lshift);
-                    self.lifx.do_send(LifxControllerSetColour {
-                        addr: b.bulb.addr.clone(),
-                        duration: lshift.duration,
-                        flicker: lshift.flicker,
-                        colour: lshift.colour.clone(),
-                    });
-                    // Set the current HSBK to what we just sent
-                    b.current = lshift.colour.clone();
-                    // Update the shift event
-                    b.last_event = t_now + time::Duration::milliseconds(lshift.duration as i64);
+                    let stale = (t_now - b.last_sent) >= ChronoDuration::seconds(REFRESH_SECS);
+                    let (colour, effect, infrared) = b.fit(&lshift);
+
+                    let tiles = if dimmed {
+                        None
+                    } else {
+                        b.shift_tiles(t_now, &mut *rng)
+                    };
+                    if let Some((tiles, duration)) = tiles {
+                        if tiles != b.tiles || b.effect.is_some() || stale {
+                            debug!("Tile shift requested to {:?}", tiles);
+                            self.lifx.do_send(LifxControllerSetTiles {
+                                addr: b.bulb.addr,
+                                target: b.bulb.target_u64(),
+                                duration,
+                                tiles: tiles.clone(),
+                            });
+                            b.current = tiles[0].pixels[0];
+                            b.tiles = tiles;
+                            b.effect = None;
+                            b.last_sent = t_now;
+                        }
+                    } else if let Some(zshift) =
+                        b.plan.shift_zones(t_now, b.zones.len()).filter(|_| !dimmed)
+                    {
+                        let zones = b.fit_each(&zshift.zones);
+                        if zones != b.zones || b.effect.is_some() || stale {
+                            debug!("Zone shift requested to {:?}", zshift);
+                            self.lifx.do_send(LifxControllerSetZones {
+                                addr: b.bulb.addr,
+                                target: b.bulb.target_u64(),
+                                duration: zshift.duration,
+                                zones: zones.clone(),
+                                extended: b.info.extended_multizone(),
+                            });
+                            b.current = zones[0];
+                            b.zones = zones;
+                            b.effect = None;
+                            b.last_sent = t_now;
+                        }
+                    } else if colour != b.current
+                        || effect != b.effect
+                        || (infrared.is_some() && infrared != b.infrared)
+                        || !b.showing_all(&colour)
+                    {
+                        debug!(dimmed, "Shift requested to {:?}", lshift);
+                        self.lifx.do_send(LifxControllerSetColour {
+                            addr: b.bulb.addr,
+                            target: b.bulb.target_u64(),
+                            duration: lshift.duration,
+                            colour,
+                            effect,
+                            infrared,
+                        });
+                        // Set the current HSBK to what we just sent
+                        b.set_all(colour);
+                        b.infrared = infrared.or(b.infrared);
+                        b.effect = effect;
+                        b.last_sent = t_now;
+                    } else if stale {
+                        // Nothing has changed, but the bulb may have missed it.
+                        // Setting the colour again would cut a running effect
+                        // short, so only the effect is sent.
+                        debug!(dimmed, "Refresh requested to {:?}", lshift);
+                        match effect {
+                            Some(effect) => self.lifx.do_send(LifxControllerSetWaveform {
+                                addr: b.bulb.addr,
+                                target: b.bulb.target_u64(),
+                                effect,
+                            }),
+                            None => self.lifx.do_send(LifxControllerSetColour {
+                                addr: b.bulb.addr,
+                                target: b.bulb.target_u64(),
+                                duration: lshift.duration,
+                                colour,
+                                effect,
+                                infrared,
+                            }),
+                        }
+                        b.last_sent = t_now;
+                    }
+
+                    // Don't look again until the transition is done and the plan
+                    // could actually give us something new, but make sure we still
+                    // wake up for the refresh.
+                    let transition = ChronoDuration::milliseconds(lshift.duration as i64);
+                    let wait = std::cmp::min(
+                        std::cmp::max(transition, b.plan.next_change(&t_now)),
+                        ChronoDuration::seconds(REFRESH_SECS),
+                    );
+                    let wait = vacant_in.map_or(wait, |v| std::cmp::min(wait, v));
+                    b.last_event = t_now + wait;
                 }
                 _ => {
-                    // log_event!(self.log_addr, "No shift for {}", b.bulb.name.as_str());
+                    trace!("No shift");
                 }
             }
         } // end for
     }
 }
 
-pub struct LightManagerPlanStartParty;
+pub struct LightManagerPlanStartParty {
+    // End the party on its own after this long.
+    pub duration: Option<ChronoDuration>,
+    pub source: Source,
+}
 
 impl Message for LightManagerPlanStartParty {
     type Result = ();
@@ -356,15 +1767,38 @@ impl Handler<LightManagerPlanStartParty> for LightManager {
         req: LightManagerPlanStartParty,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        self.bulbs.iter_mut().for_each(|mut bstate| {
-            bstate.plan = bstate.bulb.party_plan.clone();
-            // Make it change ASAP
-            bstate.last_event = time::empty_tm();
-        })
+        let t_now = self.clock.now();
+        self.party_until = req.duration.and_then(|d| {
+            let until = t_now.checked_add_signed(d);
+            if until.is_none() {
+                warn!("Party length {} is out of range, running until ended", d);
+            }
+            until
+        });
+        let (changes, party_events): (Vec<_>, Vec<_>) = self
+            .bulbs
+            .iter_mut()
+            .map(|bstate| {
+                let plan = bstate.bulb.party_plan;
+                bstate.change_plan(plan, None)
+            })
+            .unzip();
+        let until = self.party_until.map(|u| u.to_rfc3339());
+        self.audit(
+            "party_start",
+            req.source,
+            until.as_ref().map(|u| format!("until {}", u)),
+            changes,
+        );
+        let mut events = vec![StateEvent::PartyStarted { until }];
+        events.extend(party_events.into_iter().flatten());
+        self.notify(events);
     }
 }
 
-pub struct LightManagerPlanEndParty;
+pub struct LightManagerPlanEndParty {
+    pub source: Source,
+}
 
 impl Message for LightManagerPlanEndParty {
     type Result = ();
@@ -374,11 +1808,69 @@ impl Handler<LightManagerPlanEndParty> for LightManager {
     type Result = ();
 
     fn handle(&mut self, req: LightManagerPlanEndParty, _ctx: &mut Context<Self>) -> Self::Result {
-        self.bulbs.iter_mut().for_each(|mut bstate| {
-            bstate.plan = bstate.bulb.default_plan.clone();
-            // Make it change ASAP
-            bstate.last_event = time::empty_tm();
-        })
+        self.end_party(req.source)
+    }
+}
+
+/// Someone has come into or left a room.
+#[derive(Debug)]
+pub struct LightManagerPresence {
+    pub room: String,
+    pub occupied: bool,
+    pub source: Source,
+}
+
+impl Message for LightManagerPresence {
+    type Result = ();
+}
+
+impl Handler<LightManagerPresence> for LightManager {
+    type Result = ();
+
+    fn handle(&mut self, req: LightManagerPresence, _ctx: &mut Context<Self>) -> Self::Result {
+        let LightManagerPresence {
+            room,
+            occupied,
+            source,
+        } = req;
+        let t_now = self.clock.now();
+        // Sensors repeat themselves, which shouldn't restart the vacancy timer.
+        let changed = self
+            .presence
+            .get(&room)
+            .map(|p| p.occupied != occupied)
+            .unwrap_or(true);
+        if !changed {
+            return;
+        }
+        let state = if occupied { "occupied" } else { "empty" };
+        info!(room = %room, "Room is {}", state);
+        let bulbs = self
+            .bulbs
+            .iter()
+            .filter(|b| b.bulb.room.as_ref() == Some(&room))
+            .map(LightBulbState::unchanged)
+            .collect();
+        self.audit(
+            "presence",
+            source,
+            Some(format!("{} {}", room, state)),
+            bulbs,
+        );
+        self.presence.insert(
+            room.clone(),
+            PresenceState {
+                occupied,
+                since: t_now,
+            },
+        );
+        self.bulbs
+            .iter_mut()
+            .filter(|b| b.bulb.room.as_ref() == Some(&room))
+            .for_each(|b| {
+                // Make it change ASAP
+                b.last_event = clock::epoch();
+            });
     }
 }
 
@@ -394,22 +1886,12 @@ impl Handler<LightManagerBulbStatus> for LightManager {
     type Result = Option<LightBulbStatus>;
 
     fn handle(&mut self, req: LightManagerBulbStatus, _ctx: &mut Context<Self>) -> Self::Result {
-        log_event!(self.log_addr, "Bulb Status req");
-        let r = self.bulbs.iter_mut().fold(None, |acc, bstate| {
-            if acc.is_none() {
-                // Still looking for the bulb.
-                if bstate.bulb.name == req.name {
-                    // Got it!
-                    let s = bstate.status();
-                    Some(s)
-                } else {
-                    None
-                }
-            } else {
-                acc
-            }
-        });
-        log_event!(self.log_addr, "status inner: {:?}", r);
+        let r = self
+            .bulbs
+            .iter()
+            .find(|b| b.bulb.name == req.name)
+            .map(|b| b.status(&self.presence));
+        trace!(bulb = %req.name, "Status {:?}", r);
         r
     }
 }
@@ -418,37 +1900,113 @@ impl Handler<LightManagerBulbStatus> for LightManager {
 pub struct LightManagerBulbManual {
     pub name: String,
     pub hsbk: lifx_core::HSBK,
+    // Run this effect with the colour rather than holding it.
+    pub effect: Option<plans::LightEffectKind>,
+    pub source: Source,
 }
 
 impl Message for LightManagerBulbManual {
-    type Result = Option<()>;
+    type Result = Result<(), BulbError>;
 }
 
 impl Handler<LightManagerBulbManual> for LightManager {
-    type Result = Option<()>;
+    type Result = Result<(), BulbError>;
 
     fn handle(&mut self, req: LightManagerBulbManual, _ctx: &mut Context<Self>) -> Self::Result {
-        self.bulbs.iter_mut().fold(None, |acc, bstate| {
-            if acc.is_none() {
-                // Still looking for the bulb.
-                if bstate.bulb.name == req.name {
-                    // Got it!
-                    bstate.plan = plans::LightPlan::Manual(req.hsbk.clone());
-                    bstate.last_event = time::empty_tm();
-                    Some(())
-                } else {
-                    None
-                }
-            } else {
-                acc
+        let bstate = self
+            .bulbs
+            .iter_mut()
+            .find(|b| b.bulb.name == req.name)
+            .ok_or(BulbError::NotFound)?;
+
+        // Someone asking for colour should be told it won't happen, but kelvin
+        // close enough is fine.
+        let caps = bstate.capabilities();
+        if !caps.color && req.hsbk.saturation > 0 {
+            return Err(BulbError::Unsupported(format!(
+                "{} is a {} which can only do white",
+                req.name, caps.name
+            )));
+        }
+        let (hsbk, _) = caps.fit(&req.hsbk);
+
+        let plan = match req.effect {
+            Some(kind) => plans::LightPlan::Effect(kind, hsbk),
+            None => plans::LightPlan::Manual(hsbk),
+        };
+        let (change, _) = bstate.change_plan(plan, Some(hsbk));
+        let event = StateEvent::ManualOverride {
+            bulb: req.name,
+            from: change.before.plan.clone(),
+            to: change.after.plan.clone(),
+            hue: hsbk.hue,
+            sat: hsbk.saturation,
+            bri: hsbk.brightness,
+            k: hsbk.kelvin,
+        };
+        let detail = req.effect.map(|e| format!("{:?}", e));
+        self.audit("manual", req.source, detail, vec![change]);
+        self.notify(vec![event]);
+        Ok(())
+    }
+}
+
+/// Start a clean cycle for duration seconds, or stop one with None.
+#[derive(Debug)]
+pub struct LightManagerBulbClean {
+    pub name: String,
+    pub duration: Option<u32>,
+    pub source: Source,
+}
+
+impl Message for LightManagerBulbClean {
+    type Result = Result<(), BulbError>;
+}
+
+impl Handler<LightManagerBulbClean> for LightManager {
+    type Result = Result<(), BulbError>;
+
+    fn handle(&mut self, req: LightManagerBulbClean, _ctx: &mut Context<Self>) -> Self::Result {
+        let i = self
+            .bulbs
+            .iter()
+            .position(|b| b.bulb.name == req.name)
+            .ok_or(BulbError::NotFound)?;
+        let b = &self.bulbs[i];
+        match b.known_capabilities() {
+            Some(caps) if !caps.hev => {
+                return Err(BulbError::Unsupported(format!(
+                    "{} is a {} which can't clean",
+                    req.name, caps.name
+                )));
             }
-        })
+            Some(_) => self.clean(b, req.duration),
+            // A bulb that has just been registered hasn't said what it is yet, so
+            // ask, and clean once it answers if it turns out it can.
+            None => {
+                info!(bulb = %b.bulb.name, "Clean waits for the bulb's version");
+                self.lifx.do_send(LifxControllerQuery {
+                    addr: b.bulb.addr,
+                    target: b.bulb.target_u64(),
+                    msgs: vec![lifx_core::Message::GetVersion.into()],
+                });
+                self.bulbs[i].pending_clean = Some(req.duration);
+            }
+        }
+        let b = &self.bulbs[i];
+        let (command, detail) = match req.duration {
+            Some(d) => ("clean_start", Some(format!("for {}s", d))),
+            None => ("clean_stop", None),
+        };
+        self.audit(command, req.source, detail, vec![b.unchanged()]);
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct LightManagerBulbReset {
     pub name: String,
+    pub source: Source,
 }
 
 impl Message for LightManagerBulbReset {
@@ -459,22 +2017,12 @@ impl Handler<LightManagerBulbReset> for LightManager {
     type Result = Option<()>;
 
     fn handle(&mut self, req: LightManagerBulbReset, _ctx: &mut Context<Self>) -> Self::Result {
-        self.bulbs.iter_mut().fold(None, |acc, bstate| {
-            if acc.is_none() {
-                // Still looking for the bulb.
-                if bstate.bulb.name == req.name {
-                    // Found it
-                    bstate.plan = bstate.bulb.default_plan.clone();
-                    // Make it change ASAP
-                    bstate.last_event = time::empty_tm();
-                    Some(())
-                } else {
-                    None
-                }
-            } else {
-                acc
-            }
-        })
+        let bstate = self.bulbs.iter_mut().find(|b| b.bulb.name == req.name)?;
+        let plan = bstate.bulb.default_plan;
+        let (change, event) = bstate.change_plan(plan, None);
+        self.audit("reset", req.source, None, vec![change]);
+        self.notify(event.into_iter().collect());
+        Some(())
     }
 }
 
@@ -490,30 +2038,45 @@ impl Handler<LightManagerBulbReset> for LightManager {
 // Do we make multiple timers? Or one and modulo?
 
 pub struct IntervalActor {
-    log_addr: actix::Addr<LogActor>,
     lm: actix::Addr<LightManager>,
+    clock: Arc<dyn Clock>,
 }
 
 impl IntervalActor {
-    pub fn new(log_addr: actix::Addr<LogActor>, lm: actix::Addr<LightManager>) -> Self {
+    pub fn new(lm: actix::Addr<LightManager>) -> Self {
         IntervalActor {
-            log_addr: log_addr,
-            lm: lm,
+            lm,
+            clock: Arc::new(SystemClock::new(Tz::UTC)),
         }
     }
 
+    /// Without this the system clock is read in utc.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     fn bulb_shift(&mut self) {
-        // log_event!(self.log_addr, "shift ...");
         self.lm.do_send(LightManagerShift);
     }
 
+    fn bulb_discover(&mut self) {
+        self.lm.do_send(LightManagerDiscover);
+    }
+
+    fn bulb_query_info(&mut self) {
+        self.lm.do_send(LightManagerQueryInfo);
+    }
+
     // Probably need an auto daily reset here ---
-    fn end_party_daily(&mut self) {
+    pub fn end_party_daily(&mut self) {
         // If it's between 3 - 4 am //
-        let t_now = time::now();
-        if t_now.tm_hour >= 3 && t_now.tm_hour < 4 {
-            log_event!(self.log_addr, "auto-ending the party");
-            self.lm.do_send(LightManagerPlanEndParty);
+        let t_now = self.clock.now();
+        if t_now.hour() >= 3 && t_now.hour() < 4 {
+            info!("Auto-ending the party");
+            self.lm.do_send(LightManagerPlanEndParty {
+                source: Source::Scheduler,
+            });
         }
     }
 }
@@ -526,6 +2089,16 @@ impl Actor for IntervalActor {
         ctx.run_interval(Duration::from_millis(1000), move |act, _ctx| {
             act.bulb_shift();
         });
+        // Find the targets of any bulbs we don't know yet.
+        self.bulb_discover();
+        ctx.run_interval(Duration::from_secs(60), move |act, _ctx| {
+            act.bulb_discover();
+        });
+        // Device details rarely change, so don't ask often.
+        self.bulb_query_info();
+        ctx.run_interval(Duration::from_secs(300), move |act, _ctx| {
+            act.bulb_query_info();
+        });
         // Every 15 minutes
         ctx.run_interval(Duration::from_secs(900), move |act, _ctx| {
             act.end_party_daily();
@@ -0,0 +1,86 @@
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let chain = File::open(cert_path)
+        .map_err(|e| format!("Unable to open {} -> {}", cert_path.display(), e))
+        .and_then(|f| {
+            certs(&mut BufReader::new(f))
+                .map_err(|_| format!("Invalid certificate pem {}", cert_path.display()))
+        })?;
+    if chain.is_empty() {
+        return Err(format!("No certificates in {}", cert_path.display()));
+    }
+
+    // Try pkcs8 first, and then fall back to the older rsa format.
+    let open_key = || {
+        File::open(key_path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Unable to open {} -> {}", key_path.display(), e))
+    };
+    let mut keys = pkcs8_private_keys(&mut open_key()?).unwrap_or_else(|_| Vec::new());
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open_key()?).unwrap_or_else(|_| Vec::new());
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key in {}", key_path.display()))?;
+
+    let signing = sign::any_supported_type(&key)
+        .map_err(|_| format!("Unsupported private key type in {}", key_path.display()))?;
+
+    Ok(CertifiedKey::new(chain, Arc::new(signing)))
+}
+
+/// A certificate that can be swapped out under a running server, so renewals
+/// only need a SIGHUP rather than a restart.
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<CertifiedKey>,
+}
+
+impl ReloadableCert {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let current = load_certified_key(cert_path, key_path)?;
+        Ok(ReloadableCert {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Re-read the cert and key from disk. On failure the current cert is kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let next = load_certified_key(&self.cert_path, &self.key_path)?;
+        match self.current.write() {
+            Ok(mut current) => {
+                *current = next;
+                Ok(())
+            }
+            Err(_) => Err("Certificate lock poisoned".to_string()),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(
+        &self,
+        _server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        self.current.read().ok().map(|c| c.clone())
+    }
+}
+
+pub fn server_config(cert: Arc<ReloadableCert>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = cert;
+    config
+}
@@ -0,0 +1,185 @@
+// Tell other systems when something changes, by posting json to their urls.
+
+use actix::prelude::*;
+use chrono::DateTime;
+use chrono_tz::Tz;
+use hmac::{Hmac, Mac};
+use serde_derive::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+
+/// Header holding "sha256=" and the hex hmac of the body, when there is a secret.
+pub const SIGNATURE_HEADER: &str = "x-lifx-ctl-signature";
+
+// How long to wait on a receiver before counting it as a failure.
+const TIMEOUT_SECS: u64 = 10;
+
+/// Something that happened, as told to the webhooks.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StateEvent {
+    PlanChanged {
+        bulb: String,
+        from: String,
+        to: String,
+    },
+    // Someone set a colour by hand, which also changes the plan.
+    ManualOverride {
+        bulb: String,
+        from: String,
+        to: String,
+        hue: u16,
+        sat: u16,
+        bri: u16,
+        k: u16,
+    },
+    PartyStarted {
+        until: Option<String>,
+    },
+    PartyEnded,
+    // Nothing has been heard from the bulb for a while.
+    BulbOffline {
+        bulb: String,
+        last_seen: String,
+    },
+    BulbOnline {
+        bulb: String,
+    },
+}
+
+// The body of every post. A retry sends the same id, so receivers can tell.
+#[derive(Debug, Serialize)]
+struct WebhookBody<'a> {
+    id: u64,
+    at: String,
+    #[serde(flatten)]
+    event: &'a StateEvent,
+}
+
+/// The hex hmac-sha256 of body, for the receiver to check against its copy of
+/// the secret.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.input(body);
+    hex::encode(mac.result().code())
+}
+
+pub struct WebhookNotify {
+    pub at: DateTime<Tz>,
+    pub event: StateEvent,
+}
+
+impl Message for WebhookNotify {
+    type Result = ();
+}
+
+pub struct WebhookActor {
+    urls: Vec<String>,
+    secret: Option<String>,
+    retries: u32,
+    // Before the first retry, doubling for each after.
+    retry_delay: Duration,
+    next_id: u64,
+}
+
+impl WebhookActor {
+    pub fn new(urls: Vec<String>) -> Self {
+        WebhookActor {
+            urls,
+            secret: None,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            next_id: 0,
+        }
+    }
+
+    /// Sign every body with this secret.
+    pub fn with_secret(mut self, secret: String) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Try a failed post again this many times, waiting delay before the first
+    /// retry and twice as long before each one after.
+    pub fn with_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+}
+
+impl Actor for WebhookActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<WebhookNotify> for WebhookActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: WebhookNotify, _ctx: &mut Context<Self>) -> Self::Result {
+        self.next_id += 1;
+        let body = WebhookBody {
+            id: self.next_id,
+            at: msg.at.to_rfc3339(),
+            event: &msg.event,
+        };
+        let body = match serde_json::to_vec(&body) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Unable to encode webhook {:?} -> {}", msg.event, e);
+                return;
+            }
+        };
+        let signature = self
+            .secret
+            .as_ref()
+            .map(|s| format!("sha256={}", sign(s, &body)));
+        // Each url gets its own retries, so a slow one doesn't hold up the rest.
+        for url in self.urls.iter() {
+            actix::spawn(deliver(
+                url.clone(),
+                body.clone(),
+                signature.clone(),
+                self.retries,
+                self.retry_delay,
+            ));
+        }
+    }
+}
+
+async fn deliver(
+    url: String,
+    body: Vec<u8>,
+    signature: Option<String>,
+    retries: u32,
+    mut delay: Duration,
+) {
+    let client = awc::Client::build()
+        .timeout(Duration::from_secs(TIMEOUT_SECS))
+        .finish();
+    for attempt in 1..=retries + 1 {
+        let mut req = client.post(&url).header("content-type", "application/json");
+        if let Some(s) = &signature {
+            req = req.header(SIGNATURE_HEADER, s.as_str());
+        }
+        match req.send_body(body.clone()).await {
+            Ok(res) if res.status().is_success() => return,
+            Ok(res) => warn!(
+                "Webhook {} answered {} on attempt {}",
+                url,
+                res.status(),
+                attempt
+            ),
+            Err(e) => warn!("Webhook {} failed on attempt {} -> {}", url, attempt, e),
+        }
+        if attempt <= retries {
+            tokio::time::delay_for(delay).await;
+            delay *= 2;
+        }
+    }
+    error!(
+        "Giving up on webhook {} after {} attempts",
+        url,
+        retries + 1
+    );
+}
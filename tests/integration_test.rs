@@ -1,19 +1,39 @@
 extern crate actix;
+extern crate chrono;
+extern crate chrono_tz;
 extern crate futures;
 extern crate lifx_core;
 extern crate lifx_ctl;
-extern crate time;
 extern crate tokio;
 use actix::prelude::*;
+use chrono::{DateTime, NaiveDate, TimeZone, Timelike};
+use chrono_tz::Tz;
 
 use lifx_core::HSBK;
 use lifx_ctl::*;
 
-use lifx_ctl::plans::{LightPlan, LightShift};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use lifx_ctl::audit::{AuditActor, AuditQuery, Source};
+use lifx_ctl::bulbs;
+use lifx_ctl::clock::{self, Clock, ManualClock};
+use lifx_ctl::plans::{
+    self, CircadianBounds, CleanSchedule, LightEffectKind, LightPlan, LightShift, LightWaveform,
+    TimeWindow,
+};
+use lifx_ctl::products;
+use lifx_ctl::proto::{HevResult, LifxMessage, TileInfo};
+use lifx_ctl::sim::{SimBulb, SimConfig};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// A time of day to run plans at.
+fn at(t: &str) -> DateTime<Tz> {
+    plans::preview_time(t).unwrap()
+}
 
 fn assert_shift(plan: &LightPlan, time_str: &str, expect: Option<LightShift>) {
-    let t = time::strptime(time_str, "%T").unwrap();
+    let t = at(time_str);
     let shift = plan.shift(t);
     println!("T: {} -> {:?}", time_str, shift);
     assert_eq!(shift, expect);
@@ -29,7 +49,8 @@ fn plan_redshift_main() {
         "12:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -44,7 +65,8 @@ fn plan_redshift_main() {
         "16:30:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -59,7 +81,8 @@ fn plan_redshift_main() {
         "17:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -73,7 +96,8 @@ fn plan_redshift_main() {
         "18:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -88,7 +112,8 @@ fn plan_redshift_main() {
         "19:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -103,7 +128,8 @@ fn plan_redshift_main() {
         "00:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -118,7 +144,8 @@ fn plan_redshift_main() {
         "07:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -139,7 +166,8 @@ fn plan_redshift_toilet() {
         "12:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -148,13 +176,14 @@ fn plan_redshift_toilet() {
             },
         }),
     );
-    // mid evening
+    // mid evening, stepping faster while it dims
     assert_shift(
         &redshift_toilet,
         "20:00:00",
         Some(LightShift {
-            duration: 4000,
-            flicker: false,
+            duration: 800,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -169,7 +198,8 @@ fn plan_redshift_toilet() {
         "00:00:00",
         Some(LightShift {
             duration: 4000,
-            flicker: false,
+            effect: None,
+            infrared: None,
             colour: HSBK {
                 hue: 0,
                 saturation: 0,
@@ -181,36 +211,1556 @@ fn plan_redshift_toilet() {
 }
 
 #[test]
-fn simple_setup() {
-    // Build a test light bulb
-    // Attach it to the controller
-    System::run(|| {
-        let logactor_addr = LogActor {}.start();
+fn plan_effect() {
+    let blue = HSBK {
+        hue: 43634,
+        saturation: 65535,
+        brightness: 47142,
+        kelvin: 3500,
+    };
+
+    // Breathe holds the colour and lets the bulb swing the brightness down.
+    let t = at("12:00:00");
+    let shift = LightPlan::Effect(LightEffectKind::Breathe, blue)
+        .shift(t)
+        .unwrap();
+    assert_eq!(shift.colour, blue);
+    let effect = shift.effect.unwrap();
+    assert_eq!(effect.waveform, LightWaveform::Sine);
+    assert!(effect.brightness_only);
+    assert_eq!(effect.colour.brightness, 4714);
+
+    // Strobe sits dark and flashes up to the colour.
+    let shift = LightPlan::Effect(LightEffectKind::Strobe, blue)
+        .shift(t)
+        .unwrap();
+    assert_eq!(shift.colour.brightness, 0);
+    let effect = shift.effect.unwrap();
+    assert_eq!(effect.waveform, LightWaveform::Pulse);
+    assert_eq!(effect.colour, blue);
+
+    // The bulb runs it, so there is nothing to re-send.
+    assert_next_change(
+        &LightPlan::Effect(LightEffectKind::Pulse, blue),
+        "12:00:00",
+        chrono::Duration::hours(24),
+    );
+
+    // The party toilet now and then has the bulb cut out briefly, and comes back.
+    let rng = clock::seeded_rng(7);
+    let mut rng = rng.lock().unwrap();
+    let flickers: Vec<_> = (0..60)
+        .filter_map(|_| {
+            LightPlan::PartyHardToilet
+                .shift_with(t, &mut *rng)
+                .unwrap()
+                .effect
+        })
+        .collect();
+    assert!(!flickers.is_empty() && flickers.len() < 30);
+    for f in flickers {
+        assert_eq!(f.waveform, LightWaveform::Pulse);
+        assert_eq!(f.colour.brightness, 0);
+        assert!(f.transient && f.brightness_only);
+        assert!(f.cycles >= 1.0 && f.cycles < 4.0);
+    }
+}
+
+fn assert_next_change(plan: &LightPlan, time_str: &str, expect: chrono::Duration) {
+    let t = at(time_str);
+    let next = plan.next_change(&t);
+    println!("T: {} -> {:?}", time_str, next);
+    assert_eq!(next, expect);
+}
+
+#[test]
+fn plan_next_change() {
+    let redshift_main = LightPlan::RedshiftMain;
+
+    // Flat during the day, so nothing changes till the evening band at 16:00.
+    assert_next_change(&redshift_main, "12:00:00", chrono::Duration::hours(4));
+    // Ramping, so we step each minute.
+    assert_next_change(&redshift_main, "17:30:15", chrono::Duration::seconds(45));
+    // Night wraps around to the morning band.
+    assert_next_change(&redshift_main, "22:00:00", chrono::Duration::hours(10));
+
+    let redshift_toilet = LightPlan::RedshiftToilet;
+    assert_next_change(&redshift_toilet, "07:59:30", chrono::Duration::seconds(30));
+    assert_next_change(&redshift_toilet, "20:00:00", chrono::Duration::seconds(60));
+
+    // Party always wants a resend.
+    assert_next_change(
+        &LightPlan::PartyHardMain,
+        "12:00:00",
+        chrono::Duration::zero(),
+    );
+}
+
+#[test]
+fn plan_infrared() {
+    let deck = LightPlan::RedshiftDeck;
+
+    // Same colours as the main plan, with infrared only at night.
+    for t in &["03:00:00", "12:00:00", "17:30:00", "19:30:00", "22:00:00"] {
+        let main = LightPlan::RedshiftMain.shift(at(t)).unwrap();
+        let shift = deck.shift(at(t)).unwrap();
+        assert_eq!(shift.colour, main.colour);
+        assert_eq!(main.infrared, None);
+    }
+    assert_eq!(deck.shift(at("12:00:00")).unwrap().infrared, Some(0));
+    assert_eq!(deck.shift(at("19:59:00")).unwrap().infrared, Some(0));
+    assert_eq!(deck.shift(at("20:00:00")).unwrap().infrared, Some(16384));
+    assert_eq!(deck.shift(at("07:59:00")).unwrap().infrared, Some(16384));
+
+    // The infrared edges are already redshift edges.
+    assert_next_change(&deck, "12:00:00", chrono::Duration::hours(4));
+}
+
+#[test]
+fn plan_dst() {
+    let london = chrono_tz::Europe::London;
+    let main = LightPlan::RedshiftMain;
+
+    // The clocks go forward an hour overnight, so morning comes an hour sooner.
+    let spring = london.ymd(2020, 3, 28).and_hms(22, 0, 0);
+    assert_eq!(main.next_change(&spring), chrono::Duration::hours(9));
+    // And back an hour in autumn, so it comes an hour later.
+    let autumn = london.ymd(2020, 10, 24).and_hms(22, 0, 0);
+    assert_eq!(main.next_change(&autumn), chrono::Duration::hours(11));
+
+    // Plans follow the wall clock across the change.
+    let clock = ManualClock::new(london.ymd(2020, 3, 29).and_hms(0, 30, 0));
+    clock.advance(chrono::Duration::hours(1));
+    let now = clock.now();
+    assert_eq!(now.hour(), 2);
+    assert_eq!(main.shift(now), main.shift(at("02:30:00")));
 
-        let lifx_addr = LifxController::new(logactor_addr.clone()).start();
-        let lm = LightManager::new(logactor_addr.clone(), lifx_addr.clone());
-        let lmaddr = lm.start();
+    let clean = CleanSchedule {
+        hour: 3,
+        minute: 0,
+        duration: 7200,
+    };
+    clock.advance(chrono::Duration::minutes(30));
+    assert!(clean.due(&clock.now(), None));
+}
+
+#[test]
+fn time_window() {
+    let late = TimeWindow::hours(22, 2);
+    assert!(!late.contains(&at("21:59:00")));
+    assert!(late.contains(&at("22:00:00")));
+    assert!(late.contains(&at("01:59:00")));
+    assert!(!late.contains(&at("02:00:00")));
+    // Four hours, so half way at midnight.
+    assert_eq!(late.remaining(&at("22:00:00")), Some(1.0));
+    assert_eq!(late.remaining(&at("00:00:00")), Some(0.5));
+
+    let london = chrono_tz::Europe::London;
+    let utc = |y, m, d, h, min| {
+        london.from_utc_datetime(&NaiveDate::from_ymd(y, m, d).and_hms(h, min, 0))
+    };
+
+    // A 23 hour day. The clocks skip from 01:00 to 02:00, so the night is three
+    // hours long.
+    assert_eq!(late.remaining(&utc(2020, 3, 28, 23, 30)), Some(0.5));
+    assert!(late.contains(&utc(2020, 3, 29, 0, 59)));
+    assert!(!late.contains(&utc(2020, 3, 29, 1, 0)));
+    // Bands that don't cross the change are the same as any other day.
+    let main = LightPlan::RedshiftMain;
+    assert_eq!(
+        main.shift(london.ymd(2020, 3, 29).and_hms(17, 30, 0)),
+        main.shift(at("17:30:00"))
+    );
+
+    // A 25 hour day. 01:00 to 02:00 happens twice, so the night is five hours,
+    // and the second time round carries on from the first.
+    assert_eq!(late.remaining(&utc(2020, 10, 25, 0, 30)), Some(0.3));
+    assert_eq!(late.remaining(&utc(2020, 10, 25, 1, 30)), Some(0.1));
+    assert!(!late.contains(&utc(2020, 10, 25, 2, 0)));
+    assert_eq!(
+        main.shift(london.ymd(2020, 10, 25).and_hms(19, 30, 0)),
+        main.shift(at("19:30:00"))
+    );
+}
+
+#[test]
+fn plan_circadian() {
+    let bounds = CircadianBounds::default();
+    let plan = LightPlan::Circadian(bounds);
+    let colour = |t: &str| plan.shift(at(t)).unwrap().colour;
+
+    assert_eq!(colour("13:00:00").kelvin, bounds.max_kelvin);
+    assert_eq!(colour("13:00:00").brightness, bounds.max_brightness);
+    assert_eq!(colour("01:00:00").kelvin, bounds.min_kelvin);
+    assert_eq!(colour("01:00:00").brightness, bounds.min_brightness);
+    assert_eq!(colour("12:00:00").saturation, 0);
+
+    // No steps anywhere in the day, only a little at a time.
+    let mut last = colour("00:00:00");
+    for p in plan.preview(at("00:00:00"), at("00:00:00"), chrono::Duration::minutes(1)) {
+        let c = p.shift.colour;
+        assert!((c.kelvin as i32 - last.kelvin as i32).abs() <= 10);
+        assert!((c.brightness as i32 - last.brightness as i32).abs() <= 200);
+        last = c;
+    }
+    // Rising all morning.
+    assert!(colour("07:00:00").kelvin > colour("06:59:00").kelvin);
+
+    // Each bulb can have its own range.
+    let dim = LightPlan::Circadian(CircadianBounds {
+        min_kelvin: 2000,
+        max_kelvin: 3000,
+        min_brightness: 1000,
+        max_brightness: 20000,
+    });
+    let c = dim.shift(at("13:00:00")).unwrap().colour;
+    assert_eq!((c.kelvin, c.brightness), (3000, 20000));
+    assert_eq!(
+        dim.next_change(&at("13:00:00")),
+        chrono::Duration::minutes(1)
+    );
+    assert_eq!(
+        LightPlan::from_name("circadian"),
+        Some(LightPlan::Circadian(CircadianBounds::default()))
+    );
 
-        // add some bulbs
+    // A range can be a single point, but not run backwards.
+    assert!(CircadianBounds::default().check().is_ok());
+    let point = CircadianBounds {
+        min_kelvin: 3000,
+        max_kelvin: 3000,
+        ..Default::default()
+    };
+    assert!(point.check().is_ok());
+    let backwards = CircadianBounds {
+        min_brightness: 60000,
+        max_brightness: 20000,
+        ..Default::default()
+    };
+    assert!(backwards.check().is_err());
+}
+
+#[test]
+fn clean_schedule() {
+    let clean = CleanSchedule {
+        hour: 3,
+        minute: 0,
+        duration: 7200,
+    };
+
+    assert!(!clean.due(&at("02:59:00"), None));
+    assert!(clean.due(&at("03:00:00"), None));
+    // Still runs if we were down at 3am, but not if we've been down for long.
+    assert!(clean.due(&at("03:59:00"), None));
+    assert!(!clean.due(&at("04:00:00"), None));
+    // Only once a night.
+    assert!(!clean.due(&at("03:01:00"), Some(&at("03:00:00"))));
+
+    // The window wraps past midnight.
+    let late = CleanSchedule {
+        hour: 23,
+        minute: 30,
+        duration: 3600,
+    };
+    assert!(late.due(&at("00:15:00"), None));
+    assert!(!late.due(&at("00:30:00"), None));
+}
+
+#[test]
+fn plan_zones() {
+    let noon = at("12:00:00");
+    // Plans with nothing for strips leave it to shift.
+    assert_eq!(LightPlan::RedshiftMain.shift_zones(noon, 16), None);
+    assert_eq!(LightPlan::PartyHardMain.shift_zones(noon, 0), None);
+
+    let from = HSBK {
+        hue: 0,
+        saturation: 65535,
+        brightness: 65535,
+        kelvin: 3500,
+    };
+    let to = HSBK {
+        hue: 40000,
+        saturation: 65535,
+        brightness: 25535,
+        kelvin: 3500,
+    };
+    let gradient = LightPlan::Gradient(from, to).shift_zones(noon, 5).unwrap();
+    assert_eq!(gradient.zones.len(), 5);
+    assert_eq!(gradient.zones[0], from);
+    assert_eq!(gradient.zones[4], to);
+    assert_eq!(gradient.zones[2].hue, 20000);
+    assert_eq!(gradient.zones[2].brightness, 45535);
+
+    // The party scrolls along by a zone each second.
+    let next = at("12:00:01");
+    let a = LightPlan::PartyHardMain.shift_zones(noon, 10).unwrap();
+    let b = LightPlan::PartyHardMain.shift_zones(next, 10).unwrap();
+    assert_eq!(a.zones.len(), 10);
+    assert_eq!(&a.zones[1..], &b.zones[..9]);
+}
+
+#[test]
+fn plan_frames() {
+    let noon = at("12:00:00");
+    assert_eq!(LightPlan::RedshiftMain.shift_frame(noon, 8, 8), None);
+    assert_eq!(LightPlan::Flame.shift_frame(noon, 0, 8), None);
+
+    let from = HSBK {
+        hue: 0,
+        saturation: 65535,
+        brightness: 65535,
+        kelvin: 3500,
+    };
+    let to = HSBK {
+        hue: 40000,
+        saturation: 65535,
+        brightness: 65535,
+        kelvin: 3500,
+    };
+    // A candle is 5 wide and 6 high, and the gradient runs top to bottom.
+    let frame = LightPlan::Gradient(from, to)
+        .shift_frame(noon, 5, 6)
+        .unwrap();
+    assert_eq!(frame.pixels.len(), 30);
+    assert!(frame.pixels[..5].iter().all(|p| *p == from));
+    assert!(frame.pixels[25..].iter().all(|p| *p == to));
+
+    // The flame is hotter at the bottom, however it flickers.
+    let flame = LightPlan::Flame.shift_frame(noon, 8, 8).unwrap();
+    assert_eq!(flame.pixels.len(), 64);
+    assert!(flame.pixels[0].brightness < flame.pixels[63].brightness);
+}
 
+#[test]
+fn plan_preview() {
+    let plan = LightPlan::from_name("redshiftmain").unwrap();
+    assert_eq!(plan, LightPlan::RedshiftMain);
+    assert_eq!(LightPlan::from_name("Manual"), None);
+
+    let points = plan.preview(
+        plans::preview_time("18:00").unwrap(),
+        plans::preview_time("20:00").unwrap(),
+        chrono::Duration::hours(1),
+    );
+    let times: Vec<u32> = points.iter().map(|p| p.at.hour()).collect();
+    assert_eq!(times, vec![18, 19]);
+    for p in points.iter() {
+        assert_eq!(Some(&p.shift), plan.shift(p.at).as_ref());
+    }
+
+    // Running past midnight carries on into the next day.
+    let points = plan.preview(
+        plans::preview_time("23:00").unwrap(),
+        plans::preview_time("01:00").unwrap(),
+        chrono::Duration::minutes(30),
+    );
+    let times: Vec<(u32, u32)> = points
+        .iter()
+        .map(|p| (p.at.hour(), p.at.minute()))
+        .collect();
+    assert_eq!(times, vec![(23, 0), (23, 30), (0, 0), (0, 30)]);
+
+    // The same time for both is a whole day.
+    let midnight = plans::preview_time("00:00").unwrap();
+    assert_eq!(
+        plan.preview(midnight, midnight, chrono::Duration::hours(1))
+            .len(),
+        24
+    );
+    assert!(LightPlan::Pause
+        .preview(midnight, midnight, chrono::Duration::hours(1))
+        .is_empty());
+    assert!(plans::preview_time("25:00").is_err());
+}
+
+#[test]
+fn product_fit() {
+    let toilet_night = HSBK {
+        hue: 0,
+        saturation: 0,
+        brightness: 0,
+        kelvin: 150,
+    };
+
+    // A19 goes down to 1500K.
+    let a19 = products::lookup(1, 97).unwrap();
+    let (fitted, warning) = a19.fit(&toilet_night);
+    assert_eq!(fitted.kelvin, 1500);
+    assert!(warning.is_some());
+
+    // White to warm can't do colour, and tops out at 4000K.
+    let mini_ww = products::lookup(1, 50).unwrap();
+    let blue = HSBK {
+        hue: 43634,
+        saturation: 65535,
+        brightness: 47142,
+        kelvin: 6500,
+    };
+    let (fitted, warning) = mini_ww.fit(&blue);
+    assert_eq!(fitted.saturation, 0);
+    assert_eq!(fitted.kelvin, 4000);
+    assert!(warning.is_some());
+
+    // Anything in range is left alone.
+    let (fitted, warning) = a19.fit(&blue);
+    assert_eq!(fitted, blue);
+    assert_eq!(warning, None);
+
+    assert_eq!(products::lookup(2, 97), None);
+}
+
+#[test]
+fn bulbs_config() {
+    let parsed = bulbs::parse(
+        r#"
+        [[bulb]]
+        name = "lounge"
+        addr = "172.24.18.10"
+
+        [[bulb]]
+        name = "toilet"
+        addr = "172.24.18.13:56800"
+        target = "d0:73:d5:01:02:03"
+        product = 90
+        plan = "RedshiftToilet"
+        party = "partyhardtoilet"
+        room = "toilet"
+        clean = { hour = 3, minute = 0, duration = 7200 }
+
+        [[bulb]]
+        name = "strip"
+        addr = "172.24.18.30"
+        plan = "Gradient"
+        gradient = [
+            { hue = 0, saturation = 65535, brightness = 65535, kelvin = 3500 },
+            { hue = 43690, saturation = 65535, brightness = 65535, kelvin = 3500 },
+        ]
+
+        [[bulb]]
+        name = "candle"
+        addr = "172.24.18.31"
+        product = 57
+        plan = "Flame"
+
+        [[bulb]]
+        name = "bedroom"
+        addr = "172.24.18.32"
+        plan = "Circadian"
+        circadian = { min_kelvin = 2200, max_brightness = 50000 }
+        "#,
+    )
+    .expect("Failed to parse bulbs");
+
+    assert_eq!(
+        parsed,
+        vec![
+            LightBulb::new(
+                "lounge".to_string(),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 10)), 56700),
+                LightPlan::RedshiftMain,
+                LightPlan::PartyHardMain,
+            ),
+            LightBulb::new(
+                "toilet".to_string(),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 13)), 56800),
+                LightPlan::RedshiftToilet,
+                LightPlan::PartyHardToilet,
+            )
+            .with_target([0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03])
+            .with_product(90)
+            .with_room("toilet")
+            .with_clean_schedule(CleanSchedule {
+                hour: 3,
+                minute: 0,
+                duration: 7200,
+            }),
+            LightBulb::new(
+                "strip".to_string(),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 30)), 56700),
+                LightPlan::Gradient(
+                    HSBK {
+                        hue: 0,
+                        saturation: 65535,
+                        brightness: 65535,
+                        kelvin: 3500,
+                    },
+                    HSBK {
+                        hue: 43690,
+                        saturation: 65535,
+                        brightness: 65535,
+                        kelvin: 3500,
+                    },
+                ),
+                LightPlan::PartyHardMain,
+            ),
+            LightBulb::new(
+                "candle".to_string(),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 31)), 56700),
+                LightPlan::Flame,
+                LightPlan::PartyHardMain,
+            )
+            .with_product(57),
+            LightBulb::new(
+                "bedroom".to_string(),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(172, 24, 18, 32)), 56700),
+                LightPlan::Circadian(CircadianBounds {
+                    min_kelvin: 2200,
+                    max_brightness: 50000,
+                    ..Default::default()
+                }),
+                LightPlan::PartyHardMain,
+            ),
+        ]
+    );
+
+    // Mistakes are found before anything starts.
+    for bad in &[
+        "[[bulb]]\nname = \"a\"\naddr = \"nowhere\"",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\nplan = \"Disco\"",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\ntarget = \"d0:73\"",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\nproduct = 9999",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\nplan = \"Gradient\"",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\nproduct = 27\nplan = \"Flame\"",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\nplan = \"Circadian\"\n\
+         circadian = { min_kelvin = 6000 }",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\n[[bulb]]\nname = \"a\"\naddr = \"10.0.0.2\"",
+        "[[bulb]]\nname = \"a\"\naddr = \"10.0.0.1\"\ntarget = \"d0:73:d5:01:02:03\"\n\
+         [[bulb]]\nname = \"b\"\naddr = \"10.0.0.1\"\ntarget = \"d0:73:d5:01:02:03\"",
+    ] {
+        assert!(bulbs::parse(bad).is_err(), "{} should be refused", bad);
+    }
+}
+
+// The mac every sim bulb answers as, unless told otherwise.
+const SIM_TARGET: [u8; 6] = [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01];
+
+fn localhost(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+}
+
+fn spawn_sim(config: SimConfig) -> SimBulb {
+    SimBulb::spawn(localhost(0), config).expect("Failed to start sim bulb")
+}
+
+// 2020-01-01 12:00:00 UTC, in the middle of the day.
+fn noon_clock() -> Arc<ManualClock> {
+    Arc::new(ManualClock::new(Tz::UTC.timestamp(1577880000, 0)))
+}
+
+// Start a controller and manager in the running System and register the bulbs.
+// configure gives the manager whatever else the test needs first.
+fn start_manager<F>(bulbs: Vec<LightBulb>, configure: F) -> Addr<LightManager>
+where
+    F: FnOnce(LightManager) -> LightManager,
+{
+    let lifx_addr = LifxController::new(localhost(0))
+        .expect("Failed to bind lifx socket")
+        .start();
+    let lmaddr = configure(LightManager::new(lifx_addr)).start();
+    for b in bulbs {
+        lmaddr.do_send(LightManagerRegister(b));
+    }
+    lmaddr
+}
+
+// Run a test's actors in a new System, until the future that start sets them up
+// and returns is done.
+fn run_actors<F, Fut>(start: F)
+where
+    F: FnOnce() -> Fut + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    System::run(move || {
+        let test = start();
+        tokio::spawn(async move {
+            test.await;
+            actix::System::current().stop();
+        });
+    })
+    .expect("System run failed!");
+}
+
+#[test]
+fn simple_setup() {
+    let sim = spawn_sim(SimConfig::default());
+    let sim_addr = sim.addr();
+
+    let blue = HSBK {
+        hue: 43634,
+        saturation: 65535,
+        brightness: 47142,
+        kelvin: 3500,
+    };
+    let seen: Arc<Mutex<Option<LightBulbStatus>>> = Arc::new(Mutex::new(None));
+    let seen_w = seen.clone();
+
+    run_actors(move || {
         let tbulb_1 = LightBulb::new(
             "tbulb1".to_string(),
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 56700),
+            sim_addr,
+            LightPlan::Manual(blue),
+            LightPlan::PartyHardMain,
         );
-
         println!("b1: {:?}", tbulb_1);
 
-        lmaddr.try_send(LightManagerRegister(tbulb_1)).unwrap();
+        let lmaddr = start_manager(vec![tbulb_1], |lm| lm);
+        lmaddr.do_send(LightManagerDiscover);
+        lmaddr.do_send(LightManagerQueryInfo);
+        lmaddr.do_send(LightManagerShift);
 
-        tokio::spawn(async move {
-            let r = lmaddr
-                .send(LightManagerStatus)
+        async move {
+            // Give the replies time to come back through the controller.
+            tokio::time::delay_for(Duration::from_millis(500)).await;
+            let status = lmaddr
+                .send(LightManagerBulbStatus {
+                    name: "tbulb1".to_string(),
+                })
+                .await
+                .expect("Failed to get status");
+            *seen_w.lock().unwrap() = status;
+        }
+    });
+
+    // The colour made it over the wire.
+    let state = sim.state();
+    assert_eq!(state.colour, blue);
+
+    // And the replies made it back.
+    let status = seen.lock().unwrap().take().expect("No status for tbulb1");
+    assert_eq!(status.target, Some("d0:73:d5:00:00:01".to_string()));
+    assert_eq!(status.info.label, Some("sim".to_string()));
+    assert_eq!(status.product, Some("LIFX A19".to_string()));
+    assert_eq!(status.info.firmware, Some("2.80".to_string()));
+}
+
+// A set goes out once, and again only while the bulb hasn't acknowledged it.
+#[test]
+fn set_resent_until_acked() {
+    use lifx_core::{BuildOptions, Message, RawMessage};
+
+    // A stand in bulb that only acknowledges the second copy of a set colour.
+    let bulb = UdpSocket::bind(localhost(0)).expect("Failed to bind bulb");
+    bulb.set_read_timeout(Some(Duration::from_millis(100)))
+        .expect("Failed to set timeout");
+    let bulb_addr = bulb.local_addr().unwrap();
+    let listener = std::thread::spawn(move || {
+        let mut sets = Vec::new();
+        let mut buf = [0u8; 1024];
+        let deadline = std::time::Instant::now() + Duration::from_millis(2000);
+        while std::time::Instant::now() < deadline {
+            let (len, from) = match bulb.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            let raw = RawMessage::unpack(&buf[..len]).expect("Bad packet");
+            // LightSetColor
+            if raw.protocol_header.typ != 102 {
+                continue;
+            }
+            sets.push((raw.frame_addr.sequence, raw.frame_addr.ack_required));
+            if sets.len() == 2 {
+                let opts = BuildOptions {
+                    target: Some(target_to_u64(&SIM_TARGET)),
+                    source: raw.frame.source,
+                    sequence: raw.frame_addr.sequence,
+                    ..Default::default()
+                };
+                let ack = Message::Acknowledgement {
+                    seq: raw.frame_addr.sequence,
+                };
+                let bytes = RawMessage::build(&opts, ack).unwrap().pack().unwrap();
+                bulb.send_to(&bytes, from).expect("Failed to ack");
+            }
+        }
+        sets
+    });
+
+    run_actors(move || {
+        let lmaddr = start_manager(
+            vec![LightBulb::new(
+                "tbulb1".to_string(),
+                bulb_addr,
+                LightPlan::Manual(HSBK {
+                    hue: 0,
+                    saturation: 0,
+                    brightness: 65535,
+                    kelvin: 3500,
+                }),
+                LightPlan::PartyHardMain,
+            )
+            .with_target(SIM_TARGET)],
+            |lm| lm,
+        );
+        lmaddr.do_send(LightManagerShift);
+
+        async move {
+            // Long enough for a third copy, if the ack were ignored.
+            tokio::time::delay_for(Duration::from_millis(1500)).await;
+        }
+    });
+
+    let sets = listener.join().expect("Bulb thread failed");
+    assert_eq!(sets.len(), 2, "{:?}", sets);
+    assert_eq!(sets[0], sets[1]);
+    assert!(sets[0].1);
+}
+
+// A bulb that already owns a mac keeps its replies, so another bulb at the same
+// address neither learns that mac nor takes what the first bulb says.
+#[test]
+fn target_learnt_once() {
+    let sim = spawn_sim(SimConfig::default());
+    let sim_addr = sim.addr();
+
+    let seen: Arc<Mutex<Vec<Option<LightBulbStatus>>>> = Arc::new(Mutex::new(Vec::new()));
+    let seen_w = seen.clone();
+
+    run_actors(move || {
+        let bulb = |name: &str| {
+            LightBulb::new(
+                name.to_string(),
+                sim_addr,
+                LightPlan::RedshiftMain,
+                LightPlan::PartyHardMain,
+            )
+        };
+        let lmaddr = start_manager(
+            vec![bulb("owner").with_target(SIM_TARGET), bulb("other")],
+            |lm| lm,
+        );
+        lmaddr.do_send(LightManagerDiscover);
+        lmaddr.do_send(LightManagerQueryInfo);
+
+        async move {
+            tokio::time::delay_for(Duration::from_millis(500)).await;
+            for name in &["owner", "other"] {
+                let status = lmaddr
+                    .send(LightManagerBulbStatus {
+                        name: name.to_string(),
+                    })
+                    .await
+                    .expect("Failed to get status");
+                seen_w.lock().unwrap().push(status);
+            }
+        }
+    });
+
+    let seen = seen.lock().unwrap();
+    let owner = seen[0].as_ref().expect("No status for owner");
+    let other = seen[1].as_ref().expect("No status for other");
+    assert_eq!(owner.info.label, Some("sim".to_string()));
+    assert_eq!(other.target, None);
+    assert_eq!(other.info.label, None);
+}
+
+// A clean asked for straight after registering can't be judged yet, so it waits
+// for the bulb to say what it is rather than being refused.
+#[test]
+fn clean_waits_for_version() {
+    let sim = spawn_sim(SimConfig::default());
+    let sim_addr = sim.addr();
+
+    let results: Arc<Mutex<Vec<Result<(), BulbError>>>> = Arc::new(Mutex::new(Vec::new()));
+    let results_w = results.clone();
+
+    run_actors(move || {
+        let lmaddr = start_manager(
+            vec![LightBulb::new(
+                "tbulb1".to_string(),
+                sim_addr,
+                LightPlan::RedshiftMain,
+                LightPlan::PartyHardMain,
+            )
+            .with_target(SIM_TARGET)],
+            |lm| lm,
+        );
+
+        async move {
+            let clean = || LightManagerBulbClean {
+                name: "tbulb1".to_string(),
+                duration: Some(60),
+                source: Source::Scheduler,
+            };
+            let first = lmaddr.send(clean()).await.expect("Failed to clean");
+            // By now the sim has said it's an A19, which can't clean.
+            tokio::time::delay_for(Duration::from_millis(500)).await;
+            let second = lmaddr.send(clean()).await.expect("Failed to clean");
+            *results_w.lock().unwrap() = vec![first, second];
+        }
+    });
+
+    let results = results.lock().unwrap();
+    assert!(results[0].is_ok(), "{:?}", results[0]);
+    match &results[1] {
+        Err(BulbError::Unsupported(e)) => assert!(e.contains("LIFX A19"), "{}", e),
+        r => panic!("Expected unsupported, got {:?}", r),
+    }
+}
+
+#[test]
+fn sim_loss() {
+    let sim = spawn_sim(SimConfig {
+        loss: 1.0,
+        ..Default::default()
+    });
+
+    let mut packets = LifxPackets::new(1234);
+    let bytes = packets
+        .pack(None, false, lifx_core::Message::GetService)
+        .unwrap();
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    sock.send_to(&bytes, sim.addr()).unwrap();
+
+    // Everything is dropped, so nothing comes back.
+    let mut buf = [0u8; 1024];
+    assert!(sock.recv_from(&mut buf).is_err());
+    assert_eq!(sim.state().dropped, 1);
+    assert_eq!(sim.state().received, 0);
+}
+
+// Each packet's replies are delayed on their own, so several in flight come back
+// together rather than one latency after another.
+#[test]
+fn sim_latency() {
+    let latency = Duration::from_millis(300);
+    let sim = spawn_sim(SimConfig {
+        latency,
+        ..Default::default()
+    });
+
+    let mut packets = LifxPackets::new(1234);
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let start = std::time::Instant::now();
+    for _ in 0..3 {
+        let bytes = packets
+            .pack(None, false, lifx_core::Message::GetLabel)
+            .unwrap();
+        sock.send_to(&bytes, sim.addr()).unwrap();
+    }
+
+    let mut buf = [0u8; 1024];
+    for _ in 0..3 {
+        let (len, _) = sock.recv_from(&mut buf).expect("No reply from sim");
+        let (_, msg) = packets.unpack(&buf[..len]).unwrap().unwrap();
+        assert!(matches!(
+            msg,
+            LifxMessage::Core(lifx_core::Message::StateLabel { .. })
+        ));
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= latency, "{:?}", elapsed);
+    assert!(elapsed < latency * 2, "{:?}", elapsed);
+}
+
+// Strips and tiles are found and drawn on through the extended messages.
+#[test]
+fn sim_zones_and_tiles() {
+    let strip = spawn_sim(SimConfig {
+        label: "strip".to_string(),
+        product: 32,
+        zones: 16,
+        ..Default::default()
+    });
+    let tile = spawn_sim(SimConfig {
+        label: "tile".to_string(),
+        target: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x02],
+        product: 55,
+        tiles: vec![
+            TileInfo {
+                width: 8,
+                height: 8,
+            };
+            2
+        ],
+        ..Default::default()
+    });
+    let (strip_addr, tile_addr) = (strip.addr(), tile.addr());
+
+    let from = HSBK {
+        hue: 0,
+        saturation: 65535,
+        brightness: 65535,
+        kelvin: 3500,
+    };
+    let to = HSBK {
+        hue: 40000,
+        saturation: 65535,
+        brightness: 25535,
+        kelvin: 3500,
+    };
+
+    run_actors(move || {
+        let lmaddr = start_manager(
+            vec![
+                LightBulb::new(
+                    "strip".to_string(),
+                    strip_addr,
+                    LightPlan::Gradient(from, to),
+                    LightPlan::PartyHardMain,
+                )
+                .with_target(SIM_TARGET)
+                .with_product(32),
+                LightBulb::new(
+                    "tile".to_string(),
+                    tile_addr,
+                    LightPlan::Gradient(from, to),
+                    LightPlan::PartyHardMain,
+                )
+                .with_target([0xd0, 0x73, 0xd5, 0x00, 0x00, 0x02])
+                .with_product(55),
+            ],
+            |lm| lm,
+        );
+        lmaddr.do_send(LightManagerQueryInfo);
+
+        async move {
+            // Learn the shapes, then draw on them.
+            tokio::time::delay_for(Duration::from_millis(300)).await;
+            lmaddr
+                .send(LightManagerShift)
                 .await
-                .expect("Failed to register");
-            assert!(r.is_ok());
-            // Okay now stop-pu!
+                .expect("Failed to shift");
+            tokio::time::delay_for(Duration::from_millis(300)).await;
+        }
+    });
+
+    let zones = strip.state().zones;
+    assert_eq!(zones.len(), 16);
+    assert_eq!(zones[0], from);
+    assert_eq!(zones[15], to);
+
+    let tiles = tile.state().tiles;
+    assert_eq!(tiles.len(), 2);
+    for pixels in tiles.iter() {
+        assert_eq!(&pixels[..8], &[from; 8]);
+        assert_eq!(&pixels[56..], &[to; 8]);
+    }
+}
+
+// A Clean bulb starts and stops its cycle when told, even when asked before it
+// has said what it is.
+#[test]
+fn sim_hev_clean() {
+    let sim = spawn_sim(SimConfig {
+        product: 90,
+        ..Default::default()
+    });
+    let sim_addr = sim.addr();
+
+    let seen: Arc<Mutex<Vec<Option<HevStatus>>>> = Arc::new(Mutex::new(Vec::new()));
+    let seen_w = seen.clone();
+
+    run_actors(move || {
+        let lmaddr = start_manager(
+            vec![LightBulb::new(
+                "toilet".to_string(),
+                sim_addr,
+                LightPlan::RedshiftToilet,
+                LightPlan::PartyHardToilet,
+            )
+            .with_target(SIM_TARGET)],
+            |lm| lm,
+        );
+
+        async move {
+            for duration in &[Some(600), None] {
+                lmaddr
+                    .send(LightManagerBulbClean {
+                        name: "toilet".to_string(),
+                        duration: *duration,
+                        source: Source::Scheduler,
+                    })
+                    .await
+                    .expect("Failed to clean")
+                    .expect("Clean refused");
+                tokio::time::delay_for(Duration::from_millis(300)).await;
+                let status = lmaddr
+                    .send(LightManagerBulbStatus {
+                        name: "toilet".to_string(),
+                    })
+                    .await
+                    .expect("Failed to get status")
+                    .expect("No status");
+                seen_w.lock().unwrap().push(status.hev);
+            }
+        }
+    });
+
+    let seen = seen.lock().unwrap();
+    let started = seen[0].as_ref().expect("No hev status after starting");
+    assert_eq!(started.duration, 600);
+    assert!(started.remaining > 590, "{:?}", started);
+    assert_eq!(seen[1].as_ref().map(|h| h.remaining), Some(0));
+
+    let state = sim.state();
+    assert_eq!(state.hev_duration, 600);
+    assert_eq!(state.hev_until, None);
+    assert_eq!(state.hev_result, HevResult::InterruptedByLan);
+}
+
+// Run a day from noon against a clock that only moves when told, giving each
+// bulb's plan and colour every ten minutes.
+fn replay_day(seed: u64) -> Vec<(String, String, HSBK)> {
+    let sim = spawn_sim(SimConfig::default());
+    let sim_addr = sim.addr();
+
+    let trace: Arc<Mutex<Vec<(String, String, HSBK)>>> = Arc::new(Mutex::new(Vec::new()));
+    let trace_w = trace.clone();
+
+    run_actors(move || {
+        let clock = noon_clock();
+        let rng = clock::seeded_rng(seed);
+
+        let bulbs = [
+            ("lounge", LightPlan::PartyHardMain),
+            ("toilet", LightPlan::PartyHardToilet),
+        ]
+        .iter()
+        .map(|(name, party)| {
+            LightBulb::new(name.to_string(), sim_addr, LightPlan::RedshiftMain, *party)
+                .with_target(SIM_TARGET)
+        })
+        .collect();
+        let lmaddr = start_manager(bulbs, |lm| lm.with_clock(clock.clone()).with_rng(rng));
+        // Not started, so only checks the party when asked.
+        let mut interval = IntervalActor::new(lmaddr.clone()).with_clock(clock.clone());
+
+        async move {
+            for _ in 0..144 {
+                clock.advance(chrono::Duration::minutes(10));
+                let now = clock.now();
+                match (now.hour(), now.minute()) {
+                    (18, 0) => lmaddr.do_send(LightManagerPlanStartParty {
+                        duration: Some(chrono::Duration::hours(2)),
+                        source: Source::Scheduler,
+                    }),
+                    (23, 0) => lmaddr.do_send(LightManagerPlanStartParty {
+                        duration: None,
+                        source: Source::Scheduler,
+                    }),
+                    _ => {}
+                }
+                interval.end_party_daily();
+                lmaddr
+                    .send(LightManagerShift)
+                    .await
+                    .expect("Failed to shift");
+
+                for name in &["lounge", "toilet"] {
+                    let status = lmaddr
+                        .send(LightManagerBulbStatus {
+                            name: name.to_string(),
+                        })
+                        .await
+                        .expect("Failed to get status")
+                        .expect("No status");
+                    trace_w.lock().unwrap().push((
+                        now.format("%H:%M").to_string(),
+                        status.plan,
+                        status.current,
+                    ));
+                }
+            }
+        }
+    });
+
+    let day = trace.lock().unwrap().clone();
+    day
+}
+
+#[test]
+fn replay_day_deterministic() {
+    let day = replay_day(42);
+    assert_eq!(day.len(), 288);
+
+    let plan_at = |when: &str| -> Vec<String> {
+        day.iter()
+            .filter(|(t, _, _)| t == when)
+            .map(|(_, p, _)| p.clone())
+            .collect()
+    };
+    assert_eq!(plan_at("17:50"), vec!["RedshiftMain", "RedshiftMain"]);
+    assert_eq!(plan_at("18:00"), vec!["PartyHardMain", "PartyHardToilet"]);
+    assert_eq!(plan_at("19:50"), vec!["PartyHardMain", "PartyHardToilet"]);
+    // The two hour party ends on its own.
+    assert_eq!(plan_at("20:00"), vec!["RedshiftMain", "RedshiftMain"]);
+    // And the open one is ended by the daily check.
+    assert_eq!(plan_at("23:00"), vec!["PartyHardMain", "PartyHardToilet"]);
+    assert_eq!(plan_at("02:50"), vec!["PartyHardMain", "PartyHardToilet"]);
+    assert_eq!(plan_at("03:00"), vec!["RedshiftMain", "RedshiftMain"]);
+
+    // The same seed parties the same way.
+    assert_eq!(day, replay_day(42));
+}
+
+#[test]
+fn presence_payload() {
+    use lifx_ctl::presence::{broker_addr, client_id, parse_payload, room_from_topic};
+
+    assert_eq!(parse_payload(b"ON"), Some(true));
+    assert_eq!(parse_payload(b" 0\n"), Some(false));
+    assert_eq!(
+        parse_payload(br#"{"occupancy": true, "battery": 90}"#),
+        Some(true)
+    );
+    assert_eq!(parse_payload(br#"{"occupied": false}"#), Some(false));
+    assert_eq!(parse_payload(br#"{"battery": 90}"#), None);
+    assert_eq!(parse_payload(b"maybe"), None);
+
+    let filter = "lifx_ctl/presence/+";
+    assert_eq!(
+        room_from_topic(filter, "lifx_ctl/presence/toilet"),
+        Some("toilet".to_string())
+    );
+    assert_eq!(room_from_topic(filter, "lifx_ctl/other/toilet"), None);
+    assert_eq!(
+        room_from_topic(filter, "lifx_ctl/presence/toilet/battery"),
+        None
+    );
+    assert_eq!(
+        room_from_topic("zigbee2mqtt/+", "zigbee2mqtt/office"),
+        Some("office".to_string())
+    );
+
+    assert_eq!(broker_addr("mqtt.lan"), Ok(("mqtt.lan".to_string(), 1883)));
+    assert_eq!(
+        broker_addr("mqtt.lan:8883"),
+        Ok(("mqtt.lan".to_string(), 8883))
+    );
+    assert!(broker_addr("mqtt.lan:port").is_err());
+
+    assert_eq!(client_id(Some("hall")), "hall");
+    let id = client_id(None);
+    assert!(id.starts_with("lifx_ctl-"));
+    assert_ne!(id, client_id(None));
+}
+
+#[test]
+fn presence_dims_empty_room() {
+    let sim = spawn_sim(SimConfig::default());
+    let sim_addr = sim.addr();
+
+    let seen: Arc<Mutex<Vec<LightBulbStatus>>> = Arc::new(Mutex::new(Vec::new()));
+    let seen_w = seen.clone();
+
+    run_actors(move || {
+        let clock = noon_clock();
+        let lmaddr = start_manager(
+            vec![LightBulb::new(
+                "toilet".to_string(),
+                sim_addr,
+                LightPlan::RedshiftToilet,
+                LightPlan::PartyHardToilet,
+            )
+            .with_target(SIM_TARGET)
+            .with_room("toilet")],
+            |lm| lm.with_clock(clock.clone()),
+        );
+
+        async move {
+            let presence = |occupied| LightManagerPresence {
+                room: "toilet".to_string(),
+                occupied,
+                source: Source::Scheduler,
+            };
+            // Before anyone is there, after they leave, nearly five minutes on,
+            // past five minutes, when they leave again without coming back, and
+            // when they come back.
+            let steps = vec![
+                (0, None),
+                (1, Some(presence(false))),
+                (4, None),
+                (2, Some(presence(false))),
+                (1, Some(presence(true))),
+            ];
+            for (mins, msg) in steps {
+                clock.advance(chrono::Duration::minutes(mins));
+                if let Some(msg) = msg {
+                    lmaddr.send(msg).await.expect("Failed to send presence");
+                }
+                lmaddr
+                    .send(LightManagerShift)
+                    .await
+                    .expect("Failed to shift");
+                let status = lmaddr
+                    .send(LightManagerBulbStatus {
+                        name: "toilet".to_string(),
+                    })
+                    .await
+                    .expect("Failed to get status")
+                    .expect("No status");
+                seen_w.lock().unwrap().push(status);
+            }
+        }
+    });
+
+    let seen = seen.lock().unwrap();
+    let bri: Vec<u16> = seen.iter().map(|s| s.current.brightness).collect();
+    let occupied: Vec<Option<bool>> = seen.iter().map(|s| s.occupied).collect();
+    assert_eq!(bri, vec![65535, 65535, 65535, 7500, 65535]);
+    assert_eq!(
+        occupied,
+        vec![None, Some(false), Some(false), Some(false), Some(true)]
+    );
+    assert_eq!(seen[0].room, Some("toilet".to_string()));
+}
+
+#[test]
+fn webhooks_signed_and_retried() {
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use lifx_ctl::webhook::{self, WebhookActor, SIGNATURE_HEADER};
+
+    let secret = "hunter2";
+    // The signature header and body of every post, including failed ones.
+    type Posts = Vec<(Option<String>, String)>;
+    let received: Arc<Mutex<Posts>> = Arc::new(Mutex::new(Vec::new()));
+    let received_w = received.clone();
+
+    run_actors(move || {
+        let clock = noon_clock();
+
+        // A stand in receiver, which fails the first post to make sure it's retried.
+        let server = HttpServer::new(move || {
+            let received = received_w.clone();
+            App::new().route(
+                "/hook",
+                // A response is already a future, so the handler needn't be async.
+                web::post().to(move |req: HttpRequest, body: web::Bytes| {
+                    let signature = req
+                        .headers()
+                        .get(SIGNATURE_HEADER)
+                        .and_then(|h| h.to_str().ok())
+                        .map(str::to_string);
+                    let mut received = received.lock().unwrap();
+                    received.push((signature, String::from_utf8_lossy(&body).to_string()));
+                    if received.len() == 1 {
+                        HttpResponse::InternalServerError().finish()
+                    } else {
+                        HttpResponse::Ok().finish()
+                    }
+                }),
+            )
+        })
+        .bind("127.0.0.1:0")
+        .expect("Failed to bind webhook receiver");
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        server.run();
+
+        let webhooks = WebhookActor::new(vec![url])
+            .with_secret(secret.to_string())
+            .with_retries(2, Duration::from_millis(50))
+            .start();
+
+        // Nothing is listening here, so the bulb never answers.
+        let bulb_addr = localhost(9);
+        let lmaddr = start_manager(
+            vec![LightBulb::new(
+                "office".to_string(),
+                bulb_addr,
+                LightPlan::RedshiftMain,
+                LightPlan::PartyHardMain,
+            )
+            .with_target(SIM_TARGET)],
+            |lm| lm.with_clock(clock.clone()).with_webhooks(webhooks),
+        );
+
+        async move {
+            lmaddr
+                .send(LightManagerBulbManual {
+                    name: "office".to_string(),
+                    hsbk: HSBK {
+                        hue: 0,
+                        saturation: 0,
+                        brightness: 65535,
+                        kelvin: 3500,
+                    },
+                    effect: None,
+                    source: Source::Scheduler,
+                })
+                .await
+                .expect("Failed to send manual")
+                .expect("Manual failed");
+            lmaddr
+                .send(LightManagerPlanStartParty {
+                    duration: None,
+                    source: Source::Scheduler,
+                })
+                .await
+                .expect("Failed to start party");
+            lmaddr
+                .send(LightManagerPlanEndParty {
+                    source: Source::Scheduler,
+                })
+                .await
+                .expect("Failed to end party");
+            // Ending it again changes nothing, so says nothing.
+            lmaddr
+                .send(LightManagerPlanEndParty {
+                    source: Source::Scheduler,
+                })
+                .await
+                .expect("Failed to end party");
+
+            clock.advance(chrono::Duration::minutes(15));
+            lmaddr
+                .send(LightManagerQueryInfo)
+                .await
+                .expect("Failed to query");
+            lmaddr
+                .send(LifxReply {
+                    addr: bulb_addr,
+                    target: target_to_u64(&SIM_TARGET),
+                    msg: lifx_core::Message::Acknowledgement { seq: 0 }.into(),
+                })
+                .await
+                .expect("Failed to reply");
+
+            tokio::time::delay_for(Duration::from_millis(500)).await;
+        }
+    });
+
+    let received = received.lock().unwrap();
+    // One more than the events, for the retry.
+    assert_eq!(received.len(), 8);
+    for (signature, body) in received.iter() {
+        assert_eq!(
+            signature.as_ref(),
+            Some(&format!(
+                "sha256={}",
+                webhook::sign(secret, body.as_bytes())
+            ))
+        );
+    }
+
+    let mut events: Vec<serde_json::Value> = received
+        .iter()
+        .map(|(_, body)| serde_json::from_str(body).expect("Invalid json"))
+        .collect();
+    events.sort_by_key(|e| e["id"].as_u64());
+    events.dedup();
+    let names: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "manual_override",
+            "party_started",
+            "plan_changed",
+            "party_ended",
+            "plan_changed",
+            "bulb_offline",
+            "bulb_online",
+        ]
+    );
+    assert_eq!(events[0]["bulb"], "office");
+    assert_eq!(events[0]["from"], "RedshiftMain");
+    assert_eq!(events[4]["to"], "RedshiftMain");
+    assert_eq!(events[5]["at"], "2020-01-01T12:15:00+00:00");
+}
+
+#[test]
+fn audit_log() {
+    let path = std::env::temp_dir().join(format!("lifx_ctl_audit_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let results: Arc<Mutex<Vec<Vec<lifx_ctl::audit::AuditEntry>>>> =
+        Arc::new(Mutex::new(Vec::new()));
+    let results_w = results.clone();
+    let path_w = path.clone();
+
+    run_actors(move || {
+        let clock = noon_clock();
+        let audit = AuditActor::new()
+            .with_file(&path_w)
+            .expect("Failed to open audit log")
+            .start();
+        let lmaddr = start_manager(
+            vec![LightBulb::new(
+                "office".to_string(),
+                localhost(9),
+                LightPlan::RedshiftMain,
+                LightPlan::PartyHardMain,
+            )
+            .with_target(SIM_TARGET)],
+            |lm| lm.with_clock(clock.clone()).with_audit(audit.clone()),
+        );
+
+        async move {
+            let http = Source::Http {
+                client: Some("172.24.18.50".to_string()),
+                token: Some("homeassistant".to_string()),
+            };
+            lmaddr
+                .send(LightManagerBulbManual {
+                    name: "office".to_string(),
+                    hsbk: HSBK {
+                        hue: 0,
+                        saturation: 0,
+                        brightness: 65535,
+                        kelvin: 3500,
+                    },
+                    effect: None,
+                    source: http.clone(),
+                })
+                .await
+                .expect("Failed to send manual")
+                .expect("Manual failed");
+
+            clock.advance(chrono::Duration::hours(1));
+            lmaddr
+                .send(LightManagerBulbReset {
+                    name: "office".to_string(),
+                    source: http.clone(),
+                })
+                .await
+                .expect("Failed to reset");
+            lmaddr
+                .send(LightManagerPlanStartParty {
+                    duration: Some(chrono::Duration::hours(2)),
+                    source: Source::Mqtt {
+                        topic: "party/start".to_string(),
+                    },
+                })
+                .await
+                .expect("Failed to start party");
+
+            // Ending it twice by hand is recorded both times, but the daily
+            // check with no party on isn't.
+            for source in &[http.clone(), http, Source::Scheduler] {
+                lmaddr
+                    .send(LightManagerPlanEndParty {
+                        source: source.clone(),
+                    })
+                    .await
+                    .expect("Failed to end party");
+            }
+
+            for since in &[None, Some("2020-01-01T22:30:00+10:00")] {
+                let since = since.map(|s| chrono::DateTime::parse_from_rfc3339(s).unwrap());
+                let entries = audit
+                    .send(AuditQuery { since })
+                    .await
+                    .expect("Failed to query")
+                    .expect("Query failed");
+                results_w.lock().unwrap().push(entries);
+            }
+        }
+    });
+
+    let results = results.lock().unwrap();
+    let commands = |entries: &Vec<lifx_ctl::audit::AuditEntry>| -> Vec<String> {
+        entries.iter().map(|e| e.command.clone()).collect()
+    };
+    assert_eq!(
+        commands(&results[0]),
+        vec!["manual", "reset", "party_start", "party_end", "party_end"]
+    );
+    // 22:30 in Brisbane is 12:30 UTC, after the manual change.
+    assert_eq!(
+        commands(&results[1]),
+        vec!["reset", "party_start", "party_end", "party_end"]
+    );
+
+    let manual = &results[0][0];
+    assert_eq!(manual.at, "2020-01-01T12:00:00+00:00");
+    assert_eq!(
+        manual.source,
+        Source::Http {
+            client: Some("172.24.18.50".to_string()),
+            token: Some("homeassistant".to_string()),
+        }
+    );
+    assert_eq!(manual.bulbs[0].bulb, "office");
+    assert_eq!(manual.bulbs[0].before.plan, "RedshiftMain");
+    assert_eq!(manual.bulbs[0].after.colour.map(|c| c.bri), Some(65535));
+
+    let reset = &results[0][1];
+    assert_eq!(reset.bulbs[0].before.plan, manual.bulbs[0].after.plan);
+    assert_eq!(reset.bulbs[0].after.plan, "RedshiftMain");
+    assert_eq!(
+        results[0][2].detail,
+        Some("until 2020-01-01T15:00:00+00:00".to_string())
+    );
+    assert_eq!(results[0][3].bulbs[0].after.plan, "RedshiftMain");
+    assert!(results[0][4]
+        .bulbs
+        .iter()
+        .all(|b| b.before.plan == b.after.plan));
+
+    // It's all in the file, a line each.
+    let lines = std::fs::read_to_string(&path).expect("No audit log");
+    assert_eq!(lines.lines().count(), 5);
+
+    // And read back from it after a restart.
+    let reloaded: Arc<Mutex<Vec<lifx_ctl::audit::AuditEntry>>> = Arc::new(Mutex::new(Vec::new()));
+    let reloaded_w = reloaded.clone();
+    let path_w = path.clone();
+    run_actors(move || {
+        let audit = AuditActor::new()
+            .with_file(&path_w)
+            .expect("Failed to open audit log")
+            .start();
+        async move {
+            let entries = audit
+                .send(AuditQuery { since: None })
+                .await
+                .expect("Failed to query")
+                .expect("Query failed");
+            *reloaded_w.lock().unwrap() = entries;
+        }
+    });
+    assert_eq!(*reloaded.lock().unwrap(), results[0]);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn audit_http_source() {
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    let seen: Arc<Mutex<Option<Source>>> = Arc::new(Mutex::new(None));
+    let seen_w = seen.clone();
+
+    // The awc client isn't Send, so this runs on the system's own thread rather
+    // than through run_actors.
+    System::run(move || {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/who",
+                web::get().to(|req: HttpRequest| {
+                    HttpResponse::Ok().json(Source::from_http(&req, Some("sensor".to_string())))
+                }),
+            )
+        })
+        .bind("127.0.0.1:0")
+        .expect("Failed to bind");
+        let url = format!("http://{}/who", server.addrs()[0]);
+        server.run();
+
+        actix::spawn(async move {
+            // A forwarded header is the client's say so, and isn't believed.
+            let mut res = awc::Client::new()
+                .get(url)
+                .header("X-Forwarded-For", "10.0.0.1")
+                .send()
+                .await
+                .expect("Failed to ask");
+            let source = res.json::<Source>().await.expect("Not a source");
+            *seen_w.lock().unwrap() = Some(source);
             actix::System::current().stop();
         });
     })
     .expect("System run failed!");
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        Some(Source::Http {
+            client: Some("127.0.0.1".to_string()),
+            token: Some("sensor".to_string()),
+        })
+    );
 }